@@ -3,7 +3,10 @@ use miniquad::{
     conf, info, Bindings, Buffer, BufferLayout, BufferType, Context, CustomEventPostBox,
     EventHandler, Pipeline, Shader, UserData, VertexAttribute, VertexFormat,
 };
-use miniquad_websockets::{WebSocketContext, WebSocketEvent, WebSocketEventKind, WebSocketSink};
+use miniquad_websockets::{
+    ConnectRequest, OverflowPolicy, WebSocketContext, WebSocketEvent, WebSocketEventKind,
+    WebSocketSink,
+};
 use nanoserde::{DeJson, SerJson};
 use std::collections::HashMap;
 
@@ -59,9 +62,11 @@ impl Stage {
 
         info!("Starting connection");
         let mut websocket_ctx = miniquad_websockets::init(post_box).unwrap();
-        websocket_ctx
-            .start_connect((), "ws://127.0.0.1:8080/")
-            .unwrap();
+        // Only the latest mouse position matters, so don't let them back up behind each other
+        let request = ConnectRequest::new("ws://127.0.0.1:8080/")
+            .send_queue_capacity(1)
+            .overflow_policy(OverflowPolicy::DropOldest);
+        websocket_ctx.start_connect((), request).unwrap();
 
         Stage {
             state: HashMap::new(),
@@ -106,7 +111,7 @@ impl EventHandler<WebSocketEvent<()>> for Stage {
 
     fn custom_event(&mut self, _ctx: &mut Context, event_data: Box<WebSocketEvent<()>>) {
         match event_data.kind {
-            WebSocketEventKind::Connected(mut sink) => {
+            WebSocketEventKind::Connected(mut sink, _) => {
                 info!("Connected");
                 let pos = MousePos { x: -0.5, y: 0.5 };
                 sink.send(pos.serialize_json()).unwrap();
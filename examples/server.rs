@@ -55,9 +55,7 @@ async fn distribute(mut rx: mpsc::Receiver<ClientEvent>, tx: broadcast::Sender<C
                     "Districute handling new connection id: {:?} color: {:?}",
                     id, color
                 );
-                initial_tx
-                    .send(data.values().map(|x| x.clone()).collect())
-                    .unwrap();
+                initial_tx.send(data.values().cloned().collect()).unwrap();
                 let new_state = ClientState { id, color, pos };
                 data.insert(id, new_state.clone());
                 tx.send(new_state).unwrap();
@@ -1,53 +1,260 @@
 
-function string_to_rust(s) {
-    var len = s.length;
-    var ptr = wasm_exports.allocate_vec_u8(len);
+// Copies into a buffer allocated by websocket_alloc, which the rust side takes ownership of
+function bytes_to_rust(bytes) {
+    var len = bytes.byteLength;
+    var ptr = wasm_exports.websocket_alloc(len);
+    // Allocating may have grown the memory, so get the buffer afterwards
     var heap = new Uint8Array(wasm_memory.buffer, ptr, len);
-    stringToUTF8(s, heap, 0, len);
+    heap.set(bytes);
     return {
         ptr: ptr,
         len: len,
     }
 }
 
+var text_encoder = new TextEncoder();
+var text_decoder = new TextDecoder();
+
+// Decodes len bytes of UTF-8, unlike UTF8ToString this doesn't stop at a NUL
+function string_from_rust(ptr, len) {
+    return text_decoder.decode(new Uint8Array(wasm_memory.buffer, ptr, len));
+}
+
+// The length passed to rust is in UTF-8 bytes, not UTF-16 code units like s.length
+function string_to_rust(s) {
+    return bytes_to_rust(text_encoder.encode(s));
+}
+
 
+// Sockets are kept in slots that are reused once they close. The id given to rust has the slot
+// index in the low 16 bits and the slot's generation in the high 16 bits, so ids for sockets
+// that have gone don't find the socket that took over the slot.
 var websockets = {
-    open: [],
+    slots: [],
+    free_slots: [],
+    // Attempts that haven't opened yet, by key, so that they can be cancelled
+    pending: {},
 
-    start_connect: function start_connect(cb_data_ptr, url_ptr, url_len) {
-        let url = UTF8ToString(url_ptr, url_len);
+    start_connect: function start_connect(
+        cb_data_ptr,
+        key,
+        url_ptr, url_len,
+        protocols_ptr, protocols_len,
+        connect_timeout_ms,
+        heartbeat_interval_ms, heartbeat_msg_ptr, heartbeat_msg_len,
+        idle_timeout_ms,
+        delay_ms
+    ) {
+        // Timeouts and the heartbeat interval are 0 when not wanted
+        let options = {
+            url: string_from_rust(url_ptr, url_len),
+            protocols: string_from_rust(protocols_ptr, protocols_len),
+            connect_timeout_ms: connect_timeout_ms,
+            heartbeat_interval_ms: heartbeat_interval_ms,
+            heartbeat_msg: string_from_rust(heartbeat_msg_ptr, heartbeat_msg_len),
+            idle_timeout_ms: idle_timeout_ms,
+        };
+        options.protocols = options.protocols.length > 0 ? options.protocols.split(",") : [];
 
-        let ws = new WebSocket(url);
+        if (delay_ms > 0) {
+            var timer = setTimeout(function () {
+                websockets.connect(cb_data_ptr, key, options);
+            }, delay_ms);
+            websockets.pending[key] = { cb_data_ptr: cb_data_ptr, timer: timer, ws: null };
+        } else {
+            websockets.connect(cb_data_ptr, key, options);
+        }
+    },
+
+    connect: function connect(cb_data_ptr, key, options) {
+        let ws;
+        try {
+            ws = new WebSocket(options.url, options.protocols);
+        } catch (err) {
+            // A bad URL or subprotocol throws straight away. It's reported as a failed
+            // connection with no close code (0), but not until we're out of the call from rust
+            var timer = setTimeout(function () {
+                delete websockets.pending[key];
+                wasm_exports.on_connection_failed(cb_data_ptr, false, 0);
+            }, 0);
+            websockets.pending[key] = { cb_data_ptr: cb_data_ptr, timer: timer, ws: null };
+            return;
+        }
+        ws.binaryType = "arraybuffer";
+        var pending = { cb_data_ptr: cb_data_ptr, timer: null, ws: ws };
+        websockets.pending[key] = pending;
+
+        if (options.connect_timeout_ms > 0) {
+            pending.timer = setTimeout(function () {
+                delete websockets.pending[key];
+                ws.onopen = null;
+                ws.onerror = null;
+                ws.onclose = null;
+                ws.close();
+                wasm_exports.on_connection_failed(cb_data_ptr, true, 0);
+            }, options.connect_timeout_ms);
+        }
 
         ws.onopen = function () {
-            var inner_id = websockets.open.length;
-            websockets.open.push(ws);
-            var cb_data_ptr2 = wasm_exports.on_open(cb_data_ptr, inner_id);
-            console.log("Setting up onmessage")
+            clearTimeout(pending.timer);
+            delete websockets.pending[key];
+            var inner_id = websockets.add(ws);
+            var protocol = string_to_rust(ws.protocol);
+            var cb_data_ptr2 = wasm_exports.on_open(cb_data_ptr, inner_id, protocol.ptr, protocol.len);
+            // Browsers don't expose ping frames so the heartbeat is done with normal messages
+            var ping_timer = null;
+            var dead_timer = null;
+            // Once the rust side has had its last callback cb_data_ptr2 is freed, so nothing
+            // else may be passed it
+            var torn_down = false;
+            function tear_down() {
+                torn_down = true;
+                clearInterval(ping_timer);
+                clearTimeout(dead_timer);
+                ws.onmessage = null;
+                ws.onclose = null;
+                ws.onerror = null;
+                websockets.remove(inner_id);
+            }
+            function reset_dead_timer() {
+                if (options.idle_timeout_ms === 0) {
+                    return;
+                }
+                clearTimeout(dead_timer);
+                dead_timer = setTimeout(function () {
+                    if (torn_down) {
+                        return;
+                    }
+                    tear_down();
+                    ws.close();
+                    wasm_exports.on_timeout(cb_data_ptr2);
+                }, options.idle_timeout_ms);
+            }
+            if (options.heartbeat_interval_ms > 0) {
+                ping_timer = setInterval(function () {
+                    ws.send(options.heartbeat_msg);
+                }, options.heartbeat_interval_ms);
+            }
+            reset_dead_timer();
             ws.onmessage = function(event) {
-                console.log("onmessge", event)
-                var msg = string_to_rust(event.data);
-                wasm_exports.on_message(cb_data_ptr2, msg.ptr, msg.len);
+                if (torn_down) {
+                    return;
+                }
+                reset_dead_timer();
+                if (typeof event.data === "string") {
+                    var msg = string_to_rust(event.data);
+                    wasm_exports.on_message(cb_data_ptr2, msg.ptr, msg.len);
+                } else {
+                    var data = bytes_to_rust(new Uint8Array(event.data));
+                    wasm_exports.on_binary_message(cb_data_ptr2, data.ptr, data.len);
+                }
             }
             ws.onclose = function(event) {
+                if (torn_down) {
+                    return;
+                }
+                var closed_locally = ws.closed_locally === true;
+                tear_down();
                 var reason = string_to_rust(event.reason);
-                wasm_exports.on_close(cb_data_ptr2, event.code, reason.ptr, reason.len, event.wasClean);
+                wasm_exports.on_close(cb_data_ptr2, event.code, reason.ptr, reason.len, event.wasClean, closed_locally);
             }
             ws.onerror = function () {
+                if (torn_down) {
+                    return;
+                }
                 wasm_exports.on_error(cb_data_ptr2);
             }
         }
 
-        ws.onerror = function () {
-            wasm_exports.on_connection_failed(cb_data_ptr);
+        // A failed connection gets an error event with no details and then a close event, which
+        // at least has the code
+        ws.onerror = function () { }
+        ws.onclose = function (event) {
+            clearTimeout(pending.timer);
+            delete websockets.pending[key];
+            wasm_exports.on_connection_failed(cb_data_ptr, false, event.code);
+        }
+    },
+
+    add: function add(ws) {
+        var index;
+        if (websockets.free_slots.length > 0) {
+            index = websockets.free_slots.pop();
+        } else {
+            index = websockets.slots.length;
+            websockets.slots.push({ ws: null, generation: 0 });
         }
+        var slot = websockets.slots[index];
+        slot.ws = ws;
+        return ((slot.generation << 16) | index) >>> 0;
+    },
+
+    // The socket for inner_id, or null if it's gone
+    get: function get(inner_id) {
+        var slot = websockets.slots[inner_id & 0xffff];
+        if (slot === undefined || slot.generation !== inner_id >>> 16) {
+            return null;
+        }
+        return slot.ws;
+    },
+
+    remove: function remove(inner_id) {
+        var index = inner_id & 0xffff;
+        var slot = websockets.slots[index];
+        slot.ws = null;
+        slot.generation = (slot.generation + 1) & 0xffff;
+        websockets.free_slots.push(index);
+    },
+
+    cancel_connect: function cancel_connect(key) {
+        let pending = websockets.pending[key];
+        if (pending === undefined) {
+            return;
+        }
+        delete websockets.pending[key];
+        clearTimeout(pending.timer);
+        if (pending.ws !== null) {
+            pending.ws.onopen = null;
+            pending.ws.onerror = null;
+            pending.ws.onclose = null;
+            pending.ws.close();
+        }
+        wasm_exports.on_cancelled(pending.cb_data_ptr);
     },
 
     send: function send(inner_id, msg_ptr, msg_len) {
-        let ws = websockets.open[inner_id];
-        let msg = UTF8ToString(msg_ptr, msg_len);
-        console.log("Sent", msg)
+        let ws = websockets.get(inner_id);
+        if (ws === null) {
+            return;
+        }
+        let msg = string_from_rust(msg_ptr, msg_len);
         ws.send(msg);
+    },
+
+    send_binary: function send_binary(inner_id, data_ptr, data_len) {
+        let ws = websockets.get(inner_id);
+        if (ws === null) {
+            return;
+        }
+        // Copy out of wasm memory, the rust side frees its buffer once this returns
+        let data = new Uint8Array(wasm_memory.buffer, data_ptr, data_len).slice();
+        ws.send(data);
+    },
+
+    ready_state: function ready_state(inner_id) {
+        let ws = websockets.get(inner_id);
+        // A socket that's gone is reported as CLOSED
+        return ws === null ? 3 : ws.readyState;
+    },
+
+    close: function close(inner_id, code, reason_ptr, reason_len) {
+        let ws = websockets.get(inner_id);
+        if (ws === null) {
+            return;
+        }
+        let reason = string_from_rust(reason_ptr, reason_len);
+        ws.closed_locally = true;
+        ws.close(code, reason);
     }
 }
 
@@ -56,6 +263,11 @@ miniquad_add_plugin({
     register_plugin: function (importObject) {
         importObject.env.websocket_start_connect = websockets.start_connect;
         importObject.env.websocket_send = websockets.send;
+        importObject.env.websocket_send_binary = websockets.send_binary;
+        importObject.env.websocket_ready_state = websockets.ready_state;
+        importObject.env.websocket_close = websockets.close;
+        importObject.env.websocket_cancel_connect = websockets.cancel_connect;
+        importObject.env.websocket_random = Math.random;
     },
     on_init: function () { }
 });
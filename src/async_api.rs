@@ -0,0 +1,147 @@
+//! Connecting from async code, with the events as a `Stream` instead of being posted.
+//!
+//! This uses the same connection machinery as `WebSocketContext::start_connect`, so everything
+//! in the `ConnectRequest` applies. On native it runs on the tokio runtime that's calling
+//! `connect`. On wasm the stream is woken by the JS callbacks, so it works with any executor.
+
+use crate::error::{Error, Result};
+use crate::event_sink::{EventSink, PostBox};
+use crate::send_queue::OutgoingMessage;
+use crate::{CloseCode, ConnectRequest, WebSocketEvent, WebSocketEventKind, WebSocketSink};
+use futures_channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures_util::stream::{Stream, StreamExt};
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+/// Sends messages on a connection made with `connect`, following it across reconnections.
+///
+/// While reconnecting sends fail with `Error::AlreadyClosed`, as for `WebSocketSink`. Dropping
+/// this closes the connection, unless a `WebSocketSink` from the events is still around.
+pub struct AsyncSink {
+    current: Arc<Mutex<CurrentSink>>,
+}
+
+/// All the events for a connection made with `connect`, starting with `Connected`. The stream
+/// ends once the connection is closed for good.
+pub struct EventStream {
+    backlog: VecDeque<WebSocketEvent<()>>,
+    events: UnboundedReceiver<WebSocketEvent<()>>,
+}
+
+#[derive(Default)]
+struct CurrentSink {
+    sink: Option<WebSocketSink>,
+    /// Set when the `AsyncSink` is dropped so that reconnections don't keep a sink alive.
+    dropped: bool,
+}
+
+/// Passes events on to the `EventStream`, picking out the sink for the `AsyncSink` on the way.
+#[derive(Clone)]
+struct Forwarder {
+    current: Arc<Mutex<CurrentSink>>,
+    events: UnboundedSender<WebSocketEvent<()>>,
+}
+
+impl EventSink<WebSocketEvent<()>> for Forwarder {
+    fn post(&self, event: WebSocketEvent<()>) {
+        match &event.kind {
+            WebSocketEventKind::Connected(sink, _) | WebSocketEventKind::Reconnected(sink) => {
+                let mut current = self.current.lock().unwrap();
+                if !current.dropped {
+                    current.sink = Some(sink.clone());
+                }
+            }
+            _ => {}
+        }
+        let _ = self.events.unbounded_send(event);
+    }
+}
+
+/// Open a connection, resolving once it's open or has failed. If the request has a
+/// `ReconnectPolicy` failed attempts are retried before giving up.
+pub async fn connect<R: Into<ConnectRequest>>(request: R) -> Result<(AsyncSink, EventStream)> {
+    let current = Arc::new(Mutex::new(CurrentSink::default()));
+    let (tx, mut rx) = unbounded();
+    let forwarder = Forwarder {
+        current: current.clone(),
+        events: tx,
+    };
+    start(request.into(), PostBox::new(forwarder))?;
+
+    // Hold on to the events up to `Connected` so that the stream still gives them
+    let mut backlog = VecDeque::new();
+    loop {
+        match rx.next().await {
+            Some(WebSocketEvent {
+                kind: WebSocketEventKind::ConnectionFailed(err),
+                ..
+            }) => return Err(err),
+            Some(event) => {
+                let connected = matches!(event.kind, WebSocketEventKind::Connected(..));
+                backlog.push_back(event);
+                if connected {
+                    break;
+                }
+            }
+            None => return Err(Error::ConnectionClosed),
+        }
+    }
+    Ok((
+        AsyncSink { current },
+        EventStream {
+            backlog,
+            events: rx,
+        },
+    ))
+}
+
+fn start(request: ConnectRequest, post_box: PostBox<WebSocketEvent<()>>) -> Result<()> {
+    crate::imp::spawn_connect((), request, post_box)
+}
+
+impl AsyncSink {
+    /// Queue a message to be sent. With `OverflowPolicy::Block` this waits for room in the send
+    /// queue, otherwise it doesn't wait at all.
+    pub async fn send(&mut self, msg: String) -> Result<()> {
+        self.sink()?.send_async(OutgoingMessage::Text(msg)).await
+    }
+
+    pub async fn send_binary(&mut self, data: Vec<u8>) -> Result<()> {
+        self.sink()?.send_async(OutgoingMessage::Binary(data)).await
+    }
+
+    /// Start the closing handshake, see `WebSocketSink::close`.
+    pub fn close(&mut self, code: CloseCode, reason: String) -> Result<()> {
+        self.sink()?.close(code, reason)
+    }
+
+    fn sink(&self) -> Result<WebSocketSink> {
+        self.current
+            .lock()
+            .unwrap()
+            .sink
+            .clone()
+            .ok_or(Error::AlreadyClosed)
+    }
+}
+
+impl Drop for AsyncSink {
+    fn drop(&mut self) {
+        let mut current = self.current.lock().unwrap();
+        current.dropped = true;
+        current.sink = None;
+    }
+}
+
+impl Stream for EventStream {
+    type Item = WebSocketEvent<()>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(event) = self.backlog.pop_front() {
+            return Poll::Ready(Some(event));
+        }
+        self.events.poll_next_unpin(cx)
+    }
+}
@@ -0,0 +1,244 @@
+//! Keeps track of the connections started from a context, so that they can be looked up by id
+//! and all closed when it's shut down.
+
+use crate::event::{CloseCode, ConnectionState};
+use crate::send_queue::SendQueue;
+use std::any::Any;
+use std::collections::HashMap;
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+
+#[derive(Default)]
+pub(crate) struct Connections {
+    state: Mutex<State>,
+    /// Signalled whenever a connection ends.
+    ended: Condvar,
+    /// Count each open connection's entry as one of its sinks, so that it stays open when the
+    /// app drops all of its own.
+    holds_sinks: bool,
+}
+
+#[derive(Default)]
+struct State {
+    next_key: u64,
+    live: HashMap<u64, Connection>,
+    shutting_down: bool,
+}
+
+struct Connection {
+    /// The `WebSocketId`, which can be of any type as `start_connect` is generic over it.
+    id: Box<dyn Any + Send>,
+    /// The queue of the open connection, `None` while connecting or waiting to reconnect.
+    queue: Option<Arc<SendQueue>>,
+    cancelled: bool,
+    /// Wakes the task when it's cancelled while connecting or waiting to reconnect.
+    cancel: Arc<Notify>,
+}
+
+/// Returned by `start_connect` to cancel the connection.
+#[derive(Clone)]
+pub struct ConnectHandle {
+    connections: Arc<Connections>,
+    key: u64,
+}
+
+/// A connection's entry in `Connections`, removed when this is dropped.
+pub(crate) struct Registration {
+    connections: Arc<Connections>,
+    key: u64,
+    cancel: Arc<Notify>,
+}
+
+fn close_frame(code: CloseCode) -> CloseFrame<'static> {
+    CloseFrame {
+        code: u16::from(code).into(),
+        reason: "".into(),
+    }
+}
+
+impl Connection {
+    fn cancel(&mut self, code: CloseCode) {
+        self.cancelled = true;
+        self.cancel.notify();
+        if let Some(queue) = &self.queue {
+            // It's fine if the app already closed it
+            let _ = queue.close(Some(close_frame(code)));
+        }
+    }
+}
+
+impl Connections {
+    /// For a context, whose connections can be used by id without keeping a sink.
+    pub(crate) fn holding_sinks() -> Self {
+        Connections {
+            holds_sinks: true,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn register<WebSocketId: Send + 'static>(
+        self: &Arc<Self>,
+        id: WebSocketId,
+    ) -> Registration {
+        let mut state = self.state.lock().unwrap();
+        let key = state.next_key;
+        state.next_key += 1;
+        let cancel = Arc::new(Notify::new());
+        let connection = Connection {
+            id: Box::new(id),
+            queue: None,
+            cancelled: state.shutting_down,
+            cancel: cancel.clone(),
+        };
+        state.live.insert(key, connection);
+        Registration {
+            connections: self.clone(),
+            key,
+            cancel,
+        }
+    }
+
+    /// The queue of the first open connection with `id`, in the order they were started.
+    pub(crate) fn queue<WebSocketId: PartialEq + 'static>(
+        &self,
+        id: &WebSocketId,
+    ) -> Option<Arc<SendQueue>> {
+        let state = self.state.lock().unwrap();
+        state
+            .live
+            .iter()
+            .filter(|(_, connection)| connection.id.downcast_ref() == Some(id))
+            .filter_map(|(key, connection)| Some((key, connection.queue.clone()?)))
+            .filter(|(_, queue)| !queue.is_closed())
+            .min_by_key(|(key, _)| **key)
+            .map(|(_, queue)| queue)
+    }
+
+    /// The queues of all of the open connections.
+    pub(crate) fn open_queues(&self) -> Vec<Arc<SendQueue>> {
+        let state = self.state.lock().unwrap();
+        state
+            .live
+            .values()
+            .filter_map(|connection| connection.queue.clone())
+            .filter(|queue| !queue.is_closed())
+            .collect()
+    }
+
+    /// The ids of type `WebSocketId` and their states.
+    pub(crate) fn states<WebSocketId: Clone + 'static>(
+        &self,
+    ) -> Vec<(WebSocketId, ConnectionState)> {
+        let state = self.state.lock().unwrap();
+        let mut states: Vec<_> = state
+            .live
+            .iter()
+            .filter_map(|(key, connection)| {
+                let id = connection.id.downcast_ref::<WebSocketId>()?;
+                let state = match &connection.queue {
+                    None => ConnectionState::Connecting,
+                    Some(queue) => queue.state(),
+                };
+                Some((*key, id.clone(), state))
+            })
+            .collect();
+        // In the order they were started
+        states.sort_by_key(|(key, _, _)| *key);
+        states
+            .into_iter()
+            .map(|(_, id, state)| (id, state))
+            .collect()
+    }
+
+    /// Close every connection with `GoingAway` and stop any more connection attempts.
+    pub(crate) fn close_all(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.shutting_down = true;
+        for connection in state.live.values_mut() {
+            connection.cancel(CloseCode::GoingAway);
+        }
+    }
+
+    /// Wait for all of the connections to end, returns false if they didn't within `timeout`.
+    pub(crate) fn wait_until_ended(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+        while !state.live.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = self.ended.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        true
+    }
+}
+
+impl Registration {
+    /// Set the queue of the open connection, or `None` when it's ended. If the connection has
+    /// been cancelled in the meantime the new queue is closed straight away.
+    pub(crate) fn set_queue(&self, queue: Option<Arc<SendQueue>>) {
+        let mut state = self.connections.state.lock().unwrap();
+        let connection = state.live.get_mut(&self.key).unwrap();
+        if let (true, Some(queue)) = (connection.cancelled, &queue) {
+            let _ = queue.close(Some(close_frame(CloseCode::GoingAway)));
+        }
+        let old_queue = mem::replace(&mut connection.queue, queue);
+        if self.connections.holds_sinks {
+            if let Some(queue) = &connection.queue {
+                queue.add_sink();
+            }
+            if let Some(old_queue) = old_queue {
+                old_queue.remove_sink();
+            }
+        }
+    }
+
+    pub(crate) fn handle(&self) -> ConnectHandle {
+        ConnectHandle {
+            connections: self.connections.clone(),
+            key: self.key,
+        }
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        let state = self.connections.state.lock().unwrap();
+        state.live[&self.key].cancelled
+    }
+
+    /// Resolves once the connection has been cancelled, to be raced against connecting and
+    /// waiting to reconnect.
+    pub(crate) async fn cancelled(&self) {
+        while !self.is_cancelled() {
+            self.cancel.notified().await;
+        }
+    }
+}
+
+impl ConnectHandle {
+    /// Stop connecting, or waiting to reconnect, and report `Cancelled`. If the connection is
+    /// already open it's closed normally instead. Does nothing once it's closed for good.
+    pub fn cancel(&self) {
+        let mut state = self.connections.state.lock().unwrap();
+        if let Some(connection) = state.live.get_mut(&self.key) {
+            connection.cancel(CloseCode::Normal);
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut state = self.connections.state.lock().unwrap();
+        let connection = state.live.remove(&self.key);
+        drop(state);
+        if let Some(queue) = connection.and_then(|connection| connection.queue) {
+            if self.connections.holds_sinks {
+                queue.remove_sink();
+            }
+        }
+        self.connections.ended.notify_all();
+    }
+}
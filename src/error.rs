@@ -9,14 +9,16 @@ use std::result;
 use std::str;
 use std::string;
 
-use http;
+use crate::event::CloseCode;
+use crate::send_queue::OutgoingMessage;
 
-// use crate::protocol::Message;
-
-#[cfg(feature = "tls")]
+#[cfg(any(feature = "tls-native", feature = "tls-rustls"))]
 pub mod tls {
     //! TLS error wrapper module, feature-gated.
+    #[cfg(feature = "tls-native")]
     pub use native_tls::Error;
+    #[cfg(all(feature = "tls-rustls", not(feature = "tls-native")))]
+    pub use tokio_rustls::rustls::TLSError as Error;
 }
 
 /// Result type of all Tungstenite library calls.
@@ -48,7 +50,7 @@ pub enum Error {
     /// Input-output error. Apart from WouldBlock, these are generally errors with the
     /// underlying connection and you should probably consider them fatal.
     Io(io::Error),
-    #[cfg(feature = "tls")]
+    #[cfg(any(feature = "tls-native", feature = "tls-rustls"))]
     /// TLS error
     Tls(tls::Error),
     /// - When reading: buffer capacity exhausted.
@@ -57,8 +59,8 @@ pub enum Error {
     Capacity(Cow<'static, str>),
     /// Protocol violation.
     Protocol(Cow<'static, str>),
-    /// Message send queue full.
-    SendQueueFull(String),
+    /// Message send queue full. The message that couldn't be queued is handed back.
+    SendQueueFull(OutgoingMessage),
     /// UTF coding error
     Utf8,
     /// Invalid URL.
@@ -67,8 +69,27 @@ pub enum Error {
     Http(http::StatusCode),
     /// HTTP format error.
     HttpFormat(http::Error),
-    ///
+    /// Received a data frame of a type that this library doesn't support.
     UnsupportedDataFrame,
+    /// Gave up waiting for the connection to open.
+    Timeout,
+    /// There's no open connection with the id given to the `WebSocketContext`.
+    NotConnected,
+    /// The browser reported an error, only on wasm. Browsers deliberately don't say what went
+    /// wrong, so this is all there is to go on. `code` is the close code when the browser gave
+    /// one, usually 1006 (abnormal closure).
+    Browser {
+        phase: FailurePhase,
+        url: String,
+        code: Option<u16>,
+    },
+}
+
+/// What the connection was doing when the browser reported an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailurePhase {
+    Connecting,
+    Open,
 }
 
 impl fmt::Display for Error {
@@ -77,7 +98,7 @@ impl fmt::Display for Error {
             Error::ConnectionClosed => write!(f, "Connection closed normally"),
             Error::AlreadyClosed => write!(f, "Trying to work with closed connection"),
             Error::Io(ref err) => write!(f, "IO error: {}", err),
-            #[cfg(feature = "tls")]
+            #[cfg(any(feature = "tls-native", feature = "tls-rustls"))]
             Error::Tls(ref err) => write!(f, "TLS error: {}", err),
             Error::Capacity(ref msg) => write!(f, "Space limit exceeded: {}", msg),
             Error::Protocol(ref msg) => write!(f, "WebSocket protocol error: {}", msg),
@@ -90,12 +111,69 @@ impl fmt::Display for Error {
                 f,
                 "Recieved data frame type that's not supported by this library"
             ),
+            Error::Timeout => write!(f, "Timed out"),
+            Error::NotConnected => write!(f, "No open connection with that id"),
+            Error::Browser {
+                phase,
+                ref url,
+                code,
+            } => {
+                let phase = match phase {
+                    FailurePhase::Connecting => "connecting to",
+                    FailurePhase::Open => "connected to",
+                };
+                write!(f, "Browser WebSocket error while {} {}", phase, url)?;
+                match code {
+                    Some(code) => write!(f, " (close code {})", code),
+                    None => Ok(()),
+                }
+            }
         }
     }
 }
 
 impl ErrorTrait for Error {}
 
+/// The close reason has to fit in a control frame along with the close code.
+pub(crate) fn check_close_reason(reason: &str) -> Result<()> {
+    if reason.len() > 123 {
+        Err(Error::Protocol(Cow::Borrowed(
+            "Close reason must be no longer than 123 bytes",
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// `Status`, `Abnormal` and `Tls` only report how a connection ended, and reserved or bad codes
+/// aren't allowed either, so none of these can be sent in a close frame.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn check_close_code(code: CloseCode) -> Result<()> {
+    match CloseCode::from(u16::from(code)) {
+        CloseCode::Status
+        | CloseCode::Abnormal
+        | CloseCode::Tls
+        | CloseCode::Reserved(_)
+        | CloseCode::Bad(_) => Err(Error::Protocol(Cow::Owned(format!(
+            "Close code {} can't be sent",
+            code
+        )))),
+        _ => Ok(()),
+    }
+}
+
+/// Browsers throw for anything but 1000 or codes in the range 3000-4999.
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn check_browser_close_code(code: CloseCode) -> Result<()> {
+    match u16::from(code) {
+        1000 | 3000..=4999 => Ok(()),
+        _ => Err(Error::Protocol(Cow::Owned(format!(
+            "Browsers can't close with code {}",
+            code
+        )))),
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Self {
         Error::Io(err)
@@ -150,7 +228,7 @@ impl From<http::Error> for Error {
     }
 }
 
-#[cfg(feature = "tls")]
+#[cfg(any(feature = "tls-native", feature = "tls-rustls"))]
 impl From<tls::Error> for Error {
     fn from(err: tls::Error) -> Self {
         Error::Tls(err)
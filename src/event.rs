@@ -1,35 +1,200 @@
 use crate::error::Error;
 use crate::WebSocketSink;
 use std::fmt::{self, Debug, Formatter};
+use std::time::Duration;
 
 pub struct WebSocketEvent<WebSocketId> {
     pub id: WebSocketId,
     pub kind: WebSocketEventKind,
 }
 
-#[derive(Debug)]
+/// Status code used to indicate why an endpoint is closing the WebSocket connection.
+///
+/// Mirrors tungstenite's `CloseCode`.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum CloseCode {
+    /// Indicates a normal closure, meaning that the purpose for
+    /// which the connection was established has been fulfilled.
+    Normal,
+    /// Indicates that an endpoint is "going away", such as a server
+    /// going down or a browser having navigated away from a page.
+    GoingAway,
+    /// Indicates that an endpoint is terminating the connection due
+    /// to a protocol error.
+    Protocol,
+    /// Indicates that an endpoint is terminating the connection
+    /// because it has received a type of data it cannot accept (e.g., an
+    /// endpoint that understands only text data MAY send this if it
+    /// receives a binary message).
+    Unsupported,
+    /// Indicates that no status code was included in a closing frame.
+    Status,
+    /// Indicates an abnormal closure, the connection was dropped without a
+    /// closing frame being received.
+    Abnormal,
+    /// Indicates that an endpoint is terminating the connection
+    /// because it has received data within a message that was not
+    /// consistent with the type of the message (e.g., non-UTF-8 [RFC3629]
+    /// data within a text message).
+    Invalid,
+    /// Indicates that an endpoint is terminating the connection
+    /// because it has received a message that violates its policy.  This
+    /// is a generic status code that can be returned when there is no
+    /// other more suitable status code (e.g., Unsupported or Size) or if there
+    /// is a need to hide specific details about the policy.
+    Policy,
+    /// Indicates that an endpoint is terminating the connection
+    /// because it has received a message that is too big for it to
+    /// process.
+    Size,
+    /// Indicates that an endpoint (client) is terminating the
+    /// connection because it has expected the server to negotiate one or
+    /// more extension, but the server didn't return them in the response
+    /// message of the WebSocket handshake.
+    Extension,
+    /// Indicates that a server is terminating the connection because
+    /// it encountered an unexpected condition that prevented it from
+    /// fulfilling the request.
+    Error,
+    /// Indicates that the server is restarting. A client may choose to reconnect,
+    /// and if it does, it should use a randomized delay of 5-30 seconds between attempts.
+    Restart,
+    /// Indicates that the server is overloaded and the client should either connect
+    /// to a different IP (when multiple targets exist), or reconnect to the same IP
+    /// when a user has performed an action.
+    Again,
+    #[doc(hidden)]
+    Tls,
+    #[doc(hidden)]
+    Reserved(u16),
+    /// Codes in the range 3000-3999, reserved for use by libraries and frameworks.
+    Library(u16),
+    /// Codes in the range 4000-4999, reserved for private use by applications.
+    Application(u16),
+    #[doc(hidden)]
+    Bad(u16),
+}
+
+impl fmt::Display for CloseCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", u16::from(*self))
+    }
+}
+
+impl From<CloseCode> for u16 {
+    fn from(code: CloseCode) -> u16 {
+        match code {
+            CloseCode::Normal => 1000,
+            CloseCode::GoingAway => 1001,
+            CloseCode::Protocol => 1002,
+            CloseCode::Unsupported => 1003,
+            CloseCode::Status => 1005,
+            CloseCode::Abnormal => 1006,
+            CloseCode::Invalid => 1007,
+            CloseCode::Policy => 1008,
+            CloseCode::Size => 1009,
+            CloseCode::Extension => 1010,
+            CloseCode::Error => 1011,
+            CloseCode::Restart => 1012,
+            CloseCode::Again => 1013,
+            CloseCode::Tls => 1015,
+            CloseCode::Reserved(code) => code,
+            CloseCode::Library(code) => code,
+            CloseCode::Application(code) => code,
+            CloseCode::Bad(code) => code,
+        }
+    }
+}
+
+impl From<u16> for CloseCode {
+    fn from(code: u16) -> CloseCode {
+        match code {
+            1000 => CloseCode::Normal,
+            1001 => CloseCode::GoingAway,
+            1002 => CloseCode::Protocol,
+            1003 => CloseCode::Unsupported,
+            1005 => CloseCode::Status,
+            1006 => CloseCode::Abnormal,
+            1007 => CloseCode::Invalid,
+            1008 => CloseCode::Policy,
+            1009 => CloseCode::Size,
+            1010 => CloseCode::Extension,
+            1011 => CloseCode::Error,
+            1012 => CloseCode::Restart,
+            1013 => CloseCode::Again,
+            1015 => CloseCode::Tls,
+            1..=999 => CloseCode::Bad(code),
+            1016..=2999 => CloseCode::Reserved(code),
+            3000..=3999 => CloseCode::Library(code),
+            4000..=4999 => CloseCode::Application(code),
+            _ => CloseCode::Bad(code),
+        }
+    }
+}
+
+/// Why the connection was closed, as reported in a `CloseMessage` event.
+#[derive(Debug, Clone, PartialEq)]
 pub struct CloseFrame {
-    code: u32,
-    reason: String,
+    pub code: CloseCode,
+    pub reason: String,
+    /// Whether the closing handshake completed. Always true on native where we only report
+    /// close frames that were actually received.
+    pub was_clean: bool,
+}
+
+/// Where a connection has got to, from `WebSocketSink::state` or
+/// `WebSocketContext::connections`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Connecting, or waiting to reconnect. Sinks are only handed out once the connection is
+    /// open so they never report this.
+    Connecting,
+    Open,
+    /// Closed from our end, waiting for the server to finish the closing handshake. Nothing
+    /// more can be sent.
+    Closing,
+    /// The connection has ended. The context doesn't list these, they're dropped instead.
+    Closed,
 }
 
 pub enum WebSocketEventKind {
-    Connected(WebSocketSink),
+    /// The connection is open, with the subprotocol the server picked if any.
+    Connected(WebSocketSink, Option<String>),
     ConnectionFailed(Error),
     Message(String),
+    Binary(Vec<u8>),
     CloseMessage(Option<CloseFrame>),
     ConnectionClosed,
     Error(Error),
+    /// The connection failed or was lost and will be retried after `delay`. Only sent when
+    /// the request has a `ReconnectPolicy`.
+    Reconnecting {
+        attempt: u32,
+        delay: Duration,
+    },
+    /// A new connection has been opened after `Reconnecting`. The previous sink no longer works.
+    Reconnected(WebSocketSink),
+    /// Nothing was received within the `Heartbeat` or read timeout, the connection has been
+    /// closed.
+    Timeout,
+    /// Connecting, or waiting to reconnect, was stopped by `ConnectHandle::cancel` or by the
+    /// context shutting down. Nothing more is reported for the connection.
+    Cancelled,
 }
 
 impl Debug for WebSocketEventKind {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
-            WebSocketEventKind::Connected(_) => write!(f, "WebSocketEventKind::Connected(...)"),
+            WebSocketEventKind::Connected(_, protocol) => {
+                write!(f, "WebSocketEventKind::Connected(..., {:?})", protocol)
+            }
             WebSocketEventKind::ConnectionFailed(err) => {
                 write!(f, "WebSocketEventKind::ConnectionFailed({:?})", err)
             }
             WebSocketEventKind::Message(msg) => write!(f, "WebSocketEventKind::Message({:?})", msg),
+            WebSocketEventKind::Binary(data) => {
+                write!(f, "WebSocketEventKind::Binary({} bytes)", data.len())
+            }
             WebSocketEventKind::CloseMessage(frame) => {
                 write!(f, "WebSocketEventKind::CloseMessage({:?})", frame,)
             }
@@ -37,15 +202,25 @@ impl Debug for WebSocketEventKind {
                 write!(f, "WebSocketEventKind::ConnectionClosed")
             }
             WebSocketEventKind::Error(err) => write!(f, "WebSocketEventKind::Error({:?})", err),
+            WebSocketEventKind::Reconnecting { attempt, delay } => write!(
+                f,
+                "WebSocketEventKind::Reconnecting {{ attempt: {}, delay: {:?} }}",
+                attempt, delay
+            ),
+            WebSocketEventKind::Reconnected(_) => {
+                write!(f, "WebSocketEventKind::Reconnected(...)")
+            }
+            WebSocketEventKind::Timeout => write!(f, "WebSocketEventKind::Timeout"),
+            WebSocketEventKind::Cancelled => write!(f, "WebSocketEventKind::Cancelled"),
         }
     }
 }
 
 impl<WebSocketId> WebSocketEvent<WebSocketId> {
-    pub fn connected(id: WebSocketId, sink: WebSocketSink) -> Self {
+    pub fn connected(id: WebSocketId, sink: WebSocketSink, protocol: Option<String>) -> Self {
         Self {
             id,
-            kind: WebSocketEventKind::Connected(sink),
+            kind: WebSocketEventKind::Connected(sink, protocol),
         }
     }
 
@@ -63,6 +238,13 @@ impl<WebSocketId> WebSocketEvent<WebSocketId> {
         }
     }
 
+    pub fn binary(id: WebSocketId, data: Vec<u8>) -> Self {
+        Self {
+            id,
+            kind: WebSocketEventKind::Binary(data),
+        }
+    }
+
     pub fn empty_close_msg(id: WebSocketId) -> Self {
         Self {
             id,
@@ -70,10 +252,14 @@ impl<WebSocketId> WebSocketEvent<WebSocketId> {
         }
     }
 
-    pub fn close_msg(id: WebSocketId, code: u32, reason: String) -> Self {
+    pub fn close_msg(id: WebSocketId, code: CloseCode, reason: String, was_clean: bool) -> Self {
         Self {
             id,
-            kind: WebSocketEventKind::CloseMessage(Some(CloseFrame { code, reason })),
+            kind: WebSocketEventKind::CloseMessage(Some(CloseFrame {
+                code,
+                reason,
+                was_clean,
+            })),
         }
     }
 
@@ -90,4 +276,50 @@ impl<WebSocketId> WebSocketEvent<WebSocketId> {
             kind: WebSocketEventKind::Error(err),
         }
     }
+
+    pub fn reconnecting(id: WebSocketId, attempt: u32, delay: Duration) -> Self {
+        Self {
+            id,
+            kind: WebSocketEventKind::Reconnecting { attempt, delay },
+        }
+    }
+
+    pub fn reconnected(id: WebSocketId, sink: WebSocketSink) -> Self {
+        Self {
+            id,
+            kind: WebSocketEventKind::Reconnected(sink),
+        }
+    }
+
+    pub fn timeout(id: WebSocketId) -> Self {
+        Self {
+            id,
+            kind: WebSocketEventKind::Timeout,
+        }
+    }
+
+    pub fn cancelled(id: WebSocketId) -> Self {
+        Self {
+            id,
+            kind: WebSocketEventKind::Cancelled,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CloseCode;
+
+    #[test]
+    fn close_code_from_u16() {
+        assert_eq!(CloseCode::from(1001), CloseCode::GoingAway);
+        assert_eq!(CloseCode::from(3001), CloseCode::Library(3001));
+        assert_eq!(CloseCode::from(4321), CloseCode::Application(4321));
+    }
+
+    #[test]
+    fn close_code_into_u16() {
+        assert_eq!(u16::from(CloseCode::Policy), 1008);
+        assert_eq!(u16::from(CloseCode::Application(4321)), 4321);
+    }
 }
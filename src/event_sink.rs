@@ -0,0 +1,87 @@
+//! Where `WebSocketEvent`s are delivered.
+
+#[cfg(feature = "miniquad")]
+use miniquad::CustomEventPostBox;
+use std::sync::mpsc;
+
+/// Something that events can be posted to, given to `init`.
+///
+/// Implemented for `std::sync::mpsc::Sender`, closures taking the event, and with the features
+/// of the same names miniquad's `CustomEventPostBox` and `crossbeam_channel::Sender`. Each
+/// connection keeps its own clone so the sink also needs to be `Clone`, and `Send` on native
+/// where the connections run on another thread.
+pub trait EventSink<E> {
+    fn post(&self, event: E);
+}
+
+#[cfg(feature = "miniquad")]
+impl<E> EventSink<E> for CustomEventPostBox<E> {
+    fn post(&self, event: E) {
+        CustomEventPostBox::post(self, event)
+    }
+}
+
+/// Events posted after the receiver has gone are dropped.
+impl<E> EventSink<E> for mpsc::Sender<E> {
+    fn post(&self, event: E) {
+        let _ = self.send(event);
+    }
+}
+
+/// Events posted after the receiver has gone are dropped.
+#[cfg(feature = "crossbeam")]
+impl<E> EventSink<E> for crossbeam_channel::Sender<E> {
+    fn post(&self, event: E) {
+        let _ = self.send(event);
+    }
+}
+
+impl<E, F: Fn(E)> EventSink<E> for F {
+    fn post(&self, event: E) {
+        self(event)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) trait CloneSink<E>: EventSink<E> + Send {
+    fn clone_box(&self) -> Box<dyn CloneSink<E>>;
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl<E, S: EventSink<E> + Clone + Send + 'static> CloneSink<E> for S {
+    fn clone_box(&self) -> Box<dyn CloneSink<E>> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) trait CloneSink<E>: EventSink<E> {
+    fn clone_box(&self) -> Box<dyn CloneSink<E>>;
+}
+
+#[cfg(target_arch = "wasm32")]
+impl<E, S: EventSink<E> + Clone + 'static> CloneSink<E> for S {
+    fn clone_box(&self) -> Box<dyn CloneSink<E>> {
+        Box::new(self.clone())
+    }
+}
+
+/// The sink given to `init` with its type erased, so that `WebSocketContext` doesn't need
+/// another type parameter.
+pub(crate) struct PostBox<E>(Box<dyn CloneSink<E>>);
+
+impl<E> PostBox<E> {
+    pub(crate) fn new<S: CloneSink<E> + 'static>(sink: S) -> Self {
+        PostBox(Box::new(sink))
+    }
+
+    pub(crate) fn post<T: Into<E>>(&self, event: T) {
+        self.0.post(event.into())
+    }
+}
+
+impl<E> Clone for PostBox<E> {
+    fn clone(&self) -> Self {
+        PostBox(self.0.clone_box())
+    }
+}
@@ -1,20 +1,40 @@
-pub use imp::{init, WebSocketContext, WebSocketSink};
+pub use imp::{init, init_polling, ConnectHandle, WebSocketContext, WebSocketSink};
+#[cfg(not(target_arch = "wasm32"))]
+pub use native_imp::{init_headless, init_with_handle, EventReceiver};
 
-pub use crate::error::Result;
+pub use crate::async_api::{connect, AsyncSink, EventStream};
+pub use crate::error::{Error, FailurePhase, Result};
 pub use crate::event::*;
+pub use crate::event_sink::EventSink;
 #[cfg(not(target_arch = "wasm32"))]
 use crate::native_imp as imp;
-#[cfg(target_arch = "wasm32")]
+pub use crate::request::{ConnectRequest, Heartbeat, ReconnectPolicy, TlsConfig};
+pub use crate::send_queue::{OutgoingMessage, OverflowPolicy};
+#[cfg(all(target_arch = "wasm32", not(feature = "bindgen")))]
 use crate::wasm_imp as imp;
+#[cfg(all(target_arch = "wasm32", feature = "bindgen"))]
+use crate::web_sys_imp as imp;
 
+mod async_api;
+#[cfg(not(target_arch = "wasm32"))]
+mod connections;
 mod error;
 mod event;
+mod event_sink;
+mod request;
+mod send_queue;
 
 #[cfg(not(target_arch = "wasm32"))]
 mod native_imp;
+#[cfg(not(target_arch = "wasm32"))]
+mod tls;
 
 #[cfg(target_arch = "wasm32")]
+mod wasm_context;
+#[cfg(all(target_arch = "wasm32", not(feature = "bindgen")))]
 mod wasm_imp;
+#[cfg(all(target_arch = "wasm32", feature = "bindgen"))]
+mod web_sys_imp;
 
 #[cfg(test)]
 mod tests {
@@ -1,55 +1,151 @@
-use crate::error::{Error, Result};
-use crate::event::WebSocketEvent;
-use futures_util::sink::SinkExt;
-use miniquad::CustomEventPostBox;
+pub use crate::connections::ConnectHandle;
+use crate::connections::{Connections, Registration};
+use crate::error::{check_close_code, check_close_reason, Error, Result};
+use crate::event::{CloseCode, ConnectionState, WebSocketEvent};
+use crate::event_sink::{EventSink, PostBox};
+use crate::request::{ConnectRequest, TlsConfig};
+use crate::send_queue::{OutgoingMessage, OverflowPolicy, SendQueue};
+use crate::tls;
+use futures_util::future::pending;
+use futures_util::sink::{Sink, SinkExt};
+use futures_util::stream::Stream;
+use http::header::{HeaderName, HeaderValue, ORIGIN, SEC_WEBSOCKET_PROTOCOL};
+use std::borrow::Cow;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{mpsc, Arc};
 use std::thread;
+use std::time::Duration;
+use tokio::net::TcpStream;
 use tokio::runtime::{Builder, Handle};
 use tokio::select;
 use tokio::stream::StreamExt;
-use tokio::sync::mpsc::{channel, Sender};
 use tokio::sync::oneshot;
-use tokio_tungstenite::connect_async;
-use tokio_tungstenite::tungstenite::client::IntoClientRequest;
-use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio::time::{delay_for, delay_until, interval_at, timeout_at, Instant, Interval};
+use tokio_tungstenite::tungstenite::client::{uri_mode, IntoClientRequest};
+use tokio_tungstenite::tungstenite::handshake::client::{Request, Response};
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::stream::Mode;
 pub use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::tungstenite::{Error as TungError, Result as TungResult};
+use tokio_tungstenite::{client_async, WebSocketStream};
+
+/// How long dropping a context waits for the connections to close.
+const DROP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Timeouts longer than this are ignored, tokio's timer can't be set more than about two years
+/// ahead.
+const MAX_TIMEOUT: Duration = Duration::from_secs(365 * 24 * 60 * 60);
 
 pub struct WebSocketContext<EventType> {
-    post_box: CustomEventPostBox<EventType>,
+    post_box: PostBox<EventType>,
+    events: Option<mpsc::Receiver<EventType>>,
     runtime: Handle,
-    #[allow(dead_code)]
+    /// The thread running our own runtime, `None` when using one given to `init_with_handle`.
+    thread: Option<RuntimeThread>,
+    connections: Arc<Connections>,
+}
+
+struct RuntimeThread {
     thread_handle: thread::JoinHandle<()>,
-    #[allow(dead_code)]
     end_channel: oneshot::Sender<()>,
 }
-pub struct WebSocketSink(Handle, Sender<Message>);
 
-pub fn init<WebSocketId, EventType>(
-    post_box: CustomEventPostBox<EventType>,
+/// Sends messages on a connection. Clones share the connection, see `Drop` for when dropping
+/// them closes it.
+pub struct WebSocketSink(Arc<SendQueue>);
+
+/// The events from a context made with `init_headless`.
+pub struct EventReceiver<WebSocketId>(mpsc::Receiver<WebSocketEvent<WebSocketId>>);
+
+/// Start the thread that runs the connections. Events are delivered to `event_sink`.
+pub fn init<WebSocketId, EventType, S>(event_sink: S) -> Result<WebSocketContext<EventType>>
+where
+    EventType: Send + From<WebSocketEvent<WebSocketId>>,
+    WebSocketId: Clone,
+    S: EventSink<EventType> + Clone + Send + 'static,
+{
+    start(PostBox::new(event_sink), None)
+}
+
+/// Like `init` but the connections run on an existing tokio runtime, instead of one on a thread
+/// of our own. The runtime needs both IO and time enabled.
+pub fn init_with_handle<WebSocketId, EventType, S>(
+    event_sink: S,
+    handle: Handle,
 ) -> Result<WebSocketContext<EventType>>
 where
     EventType: Send + From<WebSocketEvent<WebSocketId>>,
     WebSocketId: Clone,
+    S: EventSink<EventType> + Clone + Send + 'static,
 {
-    let mut runtime = Builder::new().basic_scheduler().enable_io().build()?;
+    Ok(WebSocketContext {
+        post_box: PostBox::new(event_sink),
+        events: None,
+        runtime: handle,
+        thread: None,
+        connections: Arc::new(Connections::holding_sinks()),
+    })
+}
+
+/// Like `init` but events are kept in a queue in the context, to be collected with
+/// `WebSocketContext::poll_events` or `drain`, e.g. from `EventHandler::update`. For when
+/// there's no way to post custom events to the event loop.
+pub fn init_polling<WebSocketId, EventType>() -> Result<WebSocketContext<EventType>>
+where
+    EventType: Send + From<WebSocketEvent<WebSocketId>> + 'static,
+    WebSocketId: Clone,
+{
+    let (tx, rx) = mpsc::channel();
+    start(PostBox::new(tx), Some(rx))
+}
+
+/// Run without a window or event loop, e.g. for bots and tests. The events are read from the
+/// returned `EventReceiver`, which waits for them.
+pub fn init_headless<WebSocketId>() -> Result<(
+    WebSocketContext<WebSocketEvent<WebSocketId>>,
+    EventReceiver<WebSocketId>,
+)>
+where
+    WebSocketId: Send + 'static,
+{
+    let (tx, rx) = mpsc::channel();
+    Ok((start(PostBox::new(tx), None)?, EventReceiver(rx)))
+}
+
+fn start<EventType>(
+    post_box: PostBox<EventType>,
+    events: Option<mpsc::Receiver<EventType>>,
+) -> Result<WebSocketContext<EventType>> {
+    let mut runtime = Builder::new()
+        .basic_scheduler()
+        .enable_io()
+        .enable_time()
+        .build()?;
     let handle = runtime.handle().clone();
     let (tx, rx) = oneshot::channel();
     let thread_handle = thread::spawn(move || {
         runtime.block_on(async {
-            rx.await.unwrap();
+            // Also ends if the context is dropped without sending
+            let _ = rx.await;
         })
     });
     Ok(WebSocketContext {
         post_box,
+        events,
         runtime: handle,
-        thread_handle,
-        end_channel: tx,
+        thread: Some(RuntimeThread {
+            thread_handle,
+            end_channel: tx,
+        }),
+        connections: Arc::new(Connections::holding_sinks()),
     })
 }
 
 fn process_recv<WebSocketId, EventType>(
     id: WebSocketId,
-    post_box: &CustomEventPostBox<EventType>,
+    post_box: &PostBox<EventType>,
+    queue: &SendQueue,
     msg: Option<TungResult<Message>>,
 ) -> bool
 where
@@ -60,21 +156,26 @@ where
         Some(Ok(msg)) => {
             post_box.post(match msg {
                 Message::Text(s) => WebSocketEvent::message(id, s),
+                Message::Binary(data) => WebSocketEvent::binary(id, data),
                 Message::Close(Some(frame)) => WebSocketEvent::close_msg(
                     id,
-                    u16::from(frame.code) as _,
+                    u16::from(frame.code).into(),
                     frame.reason.into_owned(),
+                    true,
                 ),
                 Message::Close(None) => WebSocketEvent::empty_close_msg(id),
-                _ => WebSocketEvent::error(id, Error::UnsupportedDataFrame),
+                // tungstenite queues the pong replies itself
+                Message::Ping(_) | Message::Pong(_) => return true,
             });
             true
         }
         Some(Err(TungError::ConnectionClosed)) | None => {
+            queue.finish();
             post_box.post(WebSocketEvent::connection_closed(id));
             false
         }
         Some(Err(err)) => {
+            queue.finish();
             post_box.post(WebSocketEvent::error(id, err.into()));
             // TODO: some of these might by non-fatal
             false
@@ -82,76 +183,498 @@ where
     }
 }
 
+fn client_request(request: &ConnectRequest) -> Result<Request> {
+    let mut client_request = request.url.as_str().into_client_request()?;
+    let headers = client_request.headers_mut();
+    for (name, value) in &request.headers {
+        headers.append(
+            HeaderName::from_bytes(name.as_bytes())?,
+            HeaderValue::from_str(value)?,
+        );
+    }
+    if !request.protocols.is_empty() {
+        headers.insert(
+            SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_str(&request.protocols.join(", "))?,
+        );
+    }
+    if let Some(origin) = &request.origin {
+        headers.insert(ORIGIN, HeaderValue::from_str(origin)?);
+    }
+    Ok(client_request)
+}
+
+/// Open the TCP connection, wrap it in TLS for `wss://` URLs and do the opening handshake.
+async fn connect(
+    request: Request,
+    tls_config: &TlsConfig,
+) -> Result<(WebSocketStream<tls::Stream>, Response)> {
+    let mode = uri_mode(request.uri())?;
+    let host = request
+        .uri()
+        .host()
+        .ok_or(Error::Url(Cow::Borrowed("No host name in the URL")))?;
+    // IPv6 addresses keep their brackets in the URI, but can't be looked up or verified with them
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+        .to_string();
+    let port = request.uri().port_u16().unwrap_or(match mode {
+        Mode::Plain => 80,
+        Mode::Tls => 443,
+    });
+    let socket = TcpStream::connect((host.as_str(), port)).await?;
+    let stream: tls::Stream = match mode {
+        Mode::Plain => Box::new(socket),
+        Mode::Tls => tls::wrap_stream(socket, &host, tls_config).await?,
+    };
+    Ok(client_async(request, stream).await?)
+}
+
+/// A sample from [0, 1) for jittering reconnect delays. `RandomState` is randomly seeded so
+/// this is good enough without pulling in a rng.
+fn random_unit() -> f64 {
+    let hash = RandomState::new().build_hasher().finish();
+    (hash >> 11) as f64 / (1u64 << 53) as f64
+}
+
 async fn run_websocket<WebSocketId, EventType>(
     id: WebSocketId,
-    request: Request,
-    handle: Handle,
-    post_box: CustomEventPostBox<EventType>,
+    request: ConnectRequest,
+    post_box: PostBox<EventType>,
+    registration: Registration,
 ) where
     EventType: Send + From<WebSocketEvent<WebSocketId>>,
     WebSocketId: Clone,
 {
-    let (mut socket, mut to_send_rx) = match connect_async(request).await {
-        Ok((socket, _)) => {
-            let (tx, rx) = channel(2);
-            post_box.post(WebSocketEvent::connected(
-                id.clone(),
-                WebSocketSink(handle, tx),
-            ));
-            (socket, rx)
-        }
-        Err(err) => {
-            post_box.post(WebSocketEvent::connection_failed(id, err.into()));
-            return;
+    let mut attempt = 0;
+    let mut connected = false;
+    loop {
+        let connecting = async {
+            let connecting = connect(client_request(&request)?, &request.tls);
+            match request.connect_timeout.and_then(deadline_after) {
+                Some(deadline) => match timeout_at(deadline, connecting).await {
+                    Ok(result) => result,
+                    Err(_) => Err(Error::Timeout),
+                },
+                None => connecting.await,
+            }
+        };
+        let result = select! {
+            result = connecting => result,
+            _ = registration.cancelled() => {
+                post_box.post(WebSocketEvent::cancelled(id));
+                return;
+            }
+        };
+        let failure = match result {
+            Ok((socket, response)) => {
+                if registration.is_cancelled() {
+                    // Dropping the socket without a closing handshake, it was never reported
+                    post_box.post(WebSocketEvent::cancelled(id));
+                    return;
+                }
+                attempt = 0;
+                let queue = Arc::new(SendQueue::new(
+                    request.send_queue_capacity,
+                    request.overflow_policy.clone(),
+                ));
+                let sink = WebSocketSink::new(queue.clone());
+                registration.set_queue(Some(queue.clone()));
+                if connected {
+                    post_box.post(WebSocketEvent::reconnected(id.clone(), sink));
+                } else {
+                    let protocol = response
+                        .headers()
+                        .get(SEC_WEBSOCKET_PROTOCOL)
+                        .and_then(|value| value.to_str().ok())
+                        .map(String::from);
+                    post_box.post(WebSocketEvent::connected(id.clone(), sink, protocol));
+                    connected = true;
+                }
+                let closed_locally =
+                    run_connection(id.clone(), socket, &queue, &request, post_box.clone()).await;
+                queue.finish();
+                registration.set_queue(None);
+                if closed_locally {
+                    return;
+                }
+                None
+            }
+            Err(err) => Some(err),
+        };
+
+        attempt += 1;
+        let delay = request
+            .reconnect
+            .as_ref()
+            .and_then(|policy| policy.delay(attempt, random_unit()));
+        match (delay, failure) {
+            (Some(delay), _) => {
+                post_box.post(WebSocketEvent::reconnecting(id.clone(), attempt, delay));
+                select! {
+                    _ = delay_for(delay) => {}
+                    _ = registration.cancelled() => {
+                        post_box.post(WebSocketEvent::cancelled(id));
+                        return;
+                    }
+                }
+            }
+            (None, Some(err)) => {
+                post_box.post(WebSocketEvent::connection_failed(id, err));
+                return;
+            }
+            (None, None) => return,
         }
-    };
+    }
+}
 
+/// Pump messages until the connection ends. Returns true if it ended because we closed it,
+/// rather than being closed by the server or failing.
+async fn run_connection<WebSocketId, EventType, S>(
+    id: WebSocketId,
+    mut socket: S,
+    queue: &SendQueue,
+    request: &ConnectRequest,
+    post_box: PostBox<EventType>,
+) -> bool
+where
+    EventType: Send + From<WebSocketEvent<WebSocketId>>,
+    WebSocketId: Clone,
+    S: Stream<Item = TungResult<Message>> + Sink<Message, Error = TungError> + Unpin,
+{
+    // Once we've sent a close frame we stop taking messages from the sink and keep reading
+    // until the server completes the close handshake.
+    let mut closing = false;
+    let mut ping_interval = request.heartbeat.as_ref().and_then(|heartbeat| {
+        // tokio panics on a zero period, wasm also pings at most every millisecond
+        let period = heartbeat.interval.max(Duration::from_millis(1));
+        deadline_after(period).map(|start| interval_at(start, period))
+    });
+    let idle_timeout = request
+        .idle_timeout()
+        .filter(|&idle_timeout| idle_timeout <= MAX_TIMEOUT);
+    let mut last_received = Instant::now();
     loop {
+        let dead_deadline = idle_timeout.map(|idle_timeout| last_received + idle_timeout);
         select! {
             rx_msg = socket.next() => {
-                if !process_recv(id.clone(), &post_box, rx_msg) {
+                last_received = Instant::now();
+                let is_ping = matches!(rx_msg, Some(Ok(Message::Ping(_))));
+                if !process_recv(id.clone(), &post_box, queue, rx_msg) {
+                    break;
+                }
+                if is_ping {
+                    // Get the queued pong out straight away rather than on the next read
+                    if let Err(err) = socket.flush().await {
+                        queue.finish();
+                        post_box.post(WebSocketEvent::error(id, err.into()));
+                        break;
+                    }
+                }
+            }
+            _ = tick(&mut ping_interval), if !closing => {
+                if let Err(err) = socket.send(Message::Ping(Vec::new())).await {
+                    queue.finish();
+                    post_box.post(WebSocketEvent::error(id, err.into()));
                     break;
                 }
             }
-            tx_msg = to_send_rx.next() => {
-                match tx_msg {
-                    Some(msg) => match socket.send(msg).await {
-                        Ok(()) => {},
-                        Err(err) => {
-                            post_box.post(WebSocketEvent::error(id, err.into()));
-                            // TODO: some of these might by non-fatal
-                            break;
-                        }
-                    },
-                    None => break,
+            _ = delay_until_opt(dead_deadline) => {
+                queue.finish();
+                post_box.post(WebSocketEvent::timeout(id));
+                // Assume the connection is dead and don't wait for a close handshake
+                return false;
+            }
+            msg = queue.next(), if !closing => {
+                // The queue gives a close message after `close` or once the sink is dropped
+                if let Message::Close(_) = msg {
+                    closing = true;
+                }
+                match socket.send(msg).await {
+                    Ok(()) => {},
+                    Err(err) => {
+                        queue.finish();
+                        post_box.post(WebSocketEvent::error(id, err.into()));
+                        // TODO: some of these might by non-fatal
+                        break;
+                    }
                 }
             }
         }
     }
+    closing
+}
+
+/// When a timer started now for `duration` should go off, `None` if it's too long to set.
+fn deadline_after(duration: Duration) -> Option<Instant> {
+    if duration > MAX_TIMEOUT {
+        None
+    } else {
+        Some(Instant::now() + duration)
+    }
+}
+
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => pending().await,
+    }
+}
+
+async fn delay_until_opt(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => delay_until(deadline).await,
+        None => pending().await,
+    }
+}
+
+/// Start connecting on the current tokio runtime, for the async API.
+pub(crate) fn spawn_connect<WebSocketId, EventType>(
+    id: WebSocketId,
+    request: ConnectRequest,
+    post_box: PostBox<EventType>,
+) -> Result<()>
+where
+    EventType: Send + From<WebSocketEvent<WebSocketId>> + 'static,
+    WebSocketId: Send + Clone + 'static,
+{
+    client_request(&request)?;
+    let registration = Arc::new(Connections::default()).register(id.clone());
+    tokio::spawn(run_websocket(id, request, post_box, registration));
+    Ok(())
 }
 
 impl<EventType> WebSocketContext<EventType> {
-    pub fn start_connect<WebSocketId>(&mut self, id: WebSocketId, request: &str) -> Result<()>
+    /// Start connecting, the result is reported with a `Connected` or `ConnectionFailed` event.
+    ///
+    /// If the request has a `ReconnectPolicy` then failed attempts, and connections that are
+    /// lost, are retried with `Reconnecting` events reported before each new attempt.
+    ///
+    /// The returned handle can be used to cancel connecting.
+    pub fn start_connect<WebSocketId, R>(
+        &mut self,
+        id: WebSocketId,
+        request: R,
+    ) -> Result<ConnectHandle>
     where
         EventType: Send + From<WebSocketEvent<WebSocketId>> + 'static,
         WebSocketId: Send + Clone + 'static,
+        R: Into<ConnectRequest>,
     {
         let post_box = self.post_box.clone();
-        let request = request.into_client_request()?;
-        let handle = self.runtime.clone();
+        let request = request.into();
+        // Check the request up front so that mistakes are reported straight away
+        client_request(&request)?;
+        let registration = self.connections.register(id.clone());
+        let handle = registration.handle();
         self.runtime.spawn(async move {
-            run_websocket(id, request, handle, post_box).await;
+            run_websocket(id, request, post_box, registration).await;
         });
-        Ok(())
+        Ok(handle)
+    }
+
+    /// The events queued since the last call, for a context made with `init_polling`. This
+    /// doesn't wait for more. Contexts made with `init` deliver their events to the sink so
+    /// there's never anything here.
+    pub fn poll_events(&mut self) -> impl Iterator<Item = EventType> + '_ {
+        self.events.iter().flat_map(|events| events.try_iter())
+    }
+
+    /// Take all of the queued events, see `poll_events`.
+    pub fn drain(&mut self) -> Vec<EventType> {
+        self.poll_events().collect()
+    }
+
+    /// Queue a message on the open connection with `id`, following its `OverflowPolicy` as for
+    /// `WebSocketSink::send`. Fails with `Error::NotConnected` if there isn't one, e.g. while
+    /// it's still connecting. If more than one open connection has the id the one started first
+    /// is used.
+    pub fn send_to<WebSocketId, M>(&mut self, id: &WebSocketId, msg: M) -> Result<()>
+    where
+        WebSocketId: PartialEq + 'static,
+        M: Into<OutgoingMessage>,
+    {
+        let queue = self.connections.queue(id).ok_or(Error::NotConnected)?;
+        queue.push(msg.into())
+    }
+
+    /// Queue a message on every open connection, whatever the type of its id. Returns how many
+    /// it was queued on, connections that refuse it because their queue is full are skipped.
+    pub fn broadcast<M: Into<OutgoingMessage>>(&mut self, msg: M) -> usize {
+        let msg = msg.into();
+        // The lock isn't held while pushing as that can block
+        let queues = self.connections.open_queues();
+        queues
+            .iter()
+            .filter(|queue| queue.push(msg.clone()).is_ok())
+            .count()
+    }
+
+    /// Start the closing handshake on the open connection with `id`, see `WebSocketSink::close`.
+    pub fn close<WebSocketId>(
+        &mut self,
+        id: &WebSocketId,
+        code: CloseCode,
+        reason: String,
+    ) -> Result<()>
+    where
+        WebSocketId: PartialEq + 'static,
+    {
+        check_close_code(code)?;
+        check_close_reason(&reason)?;
+        let queue = self.connections.queue(id).ok_or(Error::NotConnected)?;
+        queue.close(Some(close_frame(code, reason)))
+    }
+
+    /// Whether there's an open connection with `id`.
+    pub fn is_open<WebSocketId: PartialEq + 'static>(&self, id: &WebSocketId) -> bool {
+        self.connections.queue(id).is_some()
+    }
+
+    /// The connections with ids of type `WebSocketId`, in the order they were started. Ones
+    /// that have closed for good, or failed, aren't included.
+    pub fn connections<WebSocketId: Clone + 'static>(
+        &self,
+    ) -> impl Iterator<Item = (WebSocketId, ConnectionState)> {
+        self.connections.states().into_iter()
+    }
+
+    /// Close all of the connections with `CloseCode::GoingAway`, stopping any reconnections,
+    /// and wait up to `timeout` for the closing handshakes. Then stop the runtime thread, which
+    /// abandons any connections that are left. Returns whether they all closed in time.
+    ///
+    /// With `init_with_handle` don't call this from a thread that the runtime needs to make
+    /// progress, as it blocks.
+    pub fn shutdown(mut self, timeout: Duration) -> bool {
+        self.connections.close_all();
+        let ended = self.connections.wait_until_ended(timeout);
+        self.stop_thread();
+        ended
+    }
+
+    fn stop_thread(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.end_channel.send(());
+            let _ = thread.thread_handle.join();
+        }
+    }
+}
+
+impl<EventType> Drop for WebSocketContext<EventType> {
+    /// Like `shutdown` with a timeout of a second. With `init_with_handle` this only starts
+    /// closing the connections, it doesn't wait as it might be running on the runtime.
+    fn drop(&mut self) {
+        self.connections.close_all();
+        if self.thread.is_some() {
+            self.connections.wait_until_ended(DROP_TIMEOUT);
+            self.stop_thread();
+        }
+    }
+}
+
+impl<WebSocketId> EventReceiver<WebSocketId> {
+    /// Wait for the next event.
+    pub fn recv(&self) -> Option<WebSocketEvent<WebSocketId>> {
+        self.0.recv().ok()
+    }
+
+    /// Wait for the next event, giving up with `None` after `timeout`.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<WebSocketEvent<WebSocketId>> {
+        self.0.recv_timeout(timeout).ok()
+    }
+
+    /// The next event if there's one already waiting.
+    pub fn try_recv(&self) -> Option<WebSocketEvent<WebSocketId>> {
+        self.0.try_recv().ok()
+    }
+}
+
+/// Waits for each event, like `recv`.
+impl<WebSocketId> Iterator for EventReceiver<WebSocketId> {
+    type Item = WebSocketEvent<WebSocketId>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.recv()
     }
 }
 
 impl WebSocketSink {
+    fn new(queue: Arc<SendQueue>) -> Self {
+        queue.add_sink();
+        WebSocketSink(queue)
+    }
+
+    pub(crate) async fn send_async(&mut self, msg: OutgoingMessage) -> Result<()> {
+        self.0.push_async(msg).await
+    }
+
+    /// Whether the connection is still open. Sending fails with `Error::AlreadyClosed` once it's
+    /// `Closing` or `Closed`.
+    pub fn state(&self) -> ConnectionState {
+        self.0.state()
+    }
+
+    /// Queue a message to be sent. If the queue set with `ConnectRequest::send_queue_capacity`
+    /// is full then what happens depends on the `OverflowPolicy`. Unless that's `Block` this
+    /// never waits for the network.
     pub fn send(&mut self, msg: String) -> Result<()> {
-        let sender = &mut self.1;
-        self.0
-            .block_on(async { sender.send(Message::Text(msg)).await })
-            .map_err(|_| Error::AlreadyClosed)
+        self.0.push(OutgoingMessage::Text(msg))
+    }
+
+    pub fn send_binary(&mut self, data: Vec<u8>) -> Result<()> {
+        self.0.push(OutgoingMessage::Binary(data))
+    }
+
+    /// Like `send` but never blocks, even with `OverflowPolicy::Block`. If the message can't be
+    /// queued it's handed back in `Error::SendQueueFull`.
+    pub fn try_send(&mut self, msg: String) -> Result<()> {
+        self.0.try_push(OutgoingMessage::Text(msg))
+    }
+
+    pub fn try_send_binary(&mut self, data: Vec<u8>) -> Result<()> {
+        self.0.try_push(OutgoingMessage::Binary(data))
+    }
+
+    /// Change what happens when the send queue is full, replacing the policy from the
+    /// `ConnectRequest`.
+    pub fn set_overflow_policy(&mut self, policy: OverflowPolicy) {
+        self.0.set_policy(policy);
+    }
+
+    /// Start the closing handshake. The connection isn't closed until the `CloseMessage` and
+    /// `ConnectionClosed` events are received. Nothing more can be sent after this.
+    ///
+    /// Codes that are only for reporting, like `Status` and `Abnormal`, can't be sent.
+    pub fn close(&mut self, code: CloseCode, reason: String) -> Result<()> {
+        check_close_code(code)?;
+        check_close_reason(&reason)?;
+        self.0.close(Some(close_frame(code, reason)))
+    }
+}
+
+fn close_frame(code: CloseCode, reason: String) -> CloseFrame<'static> {
+    CloseFrame {
+        code: u16::from(code).into(),
+        reason: reason.into(),
+    }
+}
+
+impl Clone for WebSocketSink {
+    fn clone(&self) -> Self {
+        WebSocketSink::new(self.0.clone())
+    }
+}
+
+impl Drop for WebSocketSink {
+    /// Dropping the last sink closes the connection normally, once anything queued has been sent.
+    /// The context counts as a sink for the connections it started, so they stay open until
+    /// they're closed with `close`, cancelled or the context is shut down.
+    fn drop(&mut self) {
+        self.0.remove_sink();
     }
 }
 
@@ -161,13 +684,12 @@ impl From<TungError> for Error {
             TungError::ConnectionClosed => Error::ConnectionClosed,
             TungError::AlreadyClosed => Error::AlreadyClosed,
             TungError::Io(err) => Error::Io(err),
-            #[cfg(feature = "tls")]
-            TungError::Tls(err) => Error::Tls(err),
             TungError::Capacity(msg) => Error::Capacity(msg),
             TungError::Protocol(msg) => Error::Protocol(msg),
             TungError::SendQueueFull(msg) => match msg {
-                Message::Text(msg) => Error::SendQueueFull(msg),
-                _ => panic!("Tried to send something other than text (and it failed)"),
+                Message::Text(msg) => Error::SendQueueFull(OutgoingMessage::Text(msg)),
+                Message::Binary(data) => Error::SendQueueFull(OutgoingMessage::Binary(data)),
+                _ => panic!("Tried to send a control frame (and it failed)"),
             },
             TungError::Utf8 => Error::Utf8,
             TungError::Url(msg) => Error::Url(msg),
@@ -0,0 +1,305 @@
+use crate::send_queue::OverflowPolicy;
+use std::time::Duration;
+
+/// The longest a `ReconnectPolicy` will wait between attempts, whatever its fields say.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Describes a connection to be opened with `WebSocketContext::start_connect`.
+///
+/// A plain URL converts into a request with no extra headers or subprotocols, so
+/// `start_connect(id, "ws://127.0.0.1:8080/")` still works.
+#[derive(Debug, Clone)]
+pub struct ConnectRequest {
+    pub(crate) url: String,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) protocols: Vec<String>,
+    pub(crate) origin: Option<String>,
+    pub(crate) reconnect: Option<ReconnectPolicy>,
+    pub(crate) heartbeat: Option<Heartbeat>,
+    pub(crate) connect_timeout: Option<Duration>,
+    pub(crate) read_timeout: Option<Duration>,
+    pub(crate) tls: TlsConfig,
+    pub(crate) send_queue_capacity: Option<usize>,
+    pub(crate) overflow_policy: OverflowPolicy,
+}
+
+/// TLS options for `wss://` connections on native, which need the `tls-native` or
+/// `tls-rustls` feature. Browsers handle TLS themselves so this is ignored on wasm.
+#[derive(Debug, Clone, Default)]
+pub struct TlsConfig {
+    pub(crate) root_certificates: Vec<Vec<u8>>,
+    pub(crate) accept_invalid_certs: bool,
+}
+
+/// How to retry when a connection attempt fails or an open connection is lost.
+///
+/// The delay before attempt `n` is `initial_delay * multiplier^(n - 1)`, capped at `max_delay`
+/// (and at a day), then reduced by a random fraction of up to `jitter` so that lots of clients don't all
+/// reconnect at the same moment.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    /// Values below 1.0 are treated as 1.0.
+    pub multiplier: f64,
+    pub max_delay: Duration,
+    /// Between 0.0 (no randomisation) and 1.0.
+    pub jitter: f64,
+    /// Give up after this many consecutive failed attempts, `None` retries forever.
+    pub max_attempts: Option<u32>,
+}
+
+/// Keep checking that an open connection is still alive.
+///
+/// A ping is sent every `interval` and if nothing at all is received for `timeout` a `Timeout`
+/// event is reported and the connection is closed (and retried if there's a `ReconnectPolicy`).
+///
+/// Browsers don't expose ping frames so on wasm `fallback_message` is sent as a text message
+/// instead. The server needs to reply to it, or send something else, within `timeout`.
+#[derive(Debug, Clone)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub fallback_message: String,
+}
+
+impl ConnectRequest {
+    pub fn new<S: Into<String>>(url: S) -> Self {
+        ConnectRequest {
+            url: url.into(),
+            headers: Vec::new(),
+            protocols: Vec::new(),
+            origin: None,
+            reconnect: None,
+            heartbeat: None,
+            connect_timeout: None,
+            read_timeout: None,
+            tls: TlsConfig::default(),
+            send_queue_capacity: None,
+            overflow_policy: OverflowPolicy::default(),
+        }
+    }
+
+    /// Add a header to the opening handshake.
+    ///
+    /// Browsers don't allow setting headers on websocket requests so these are ignored on wasm.
+    pub fn header<N: Into<String>, V: Into<String>>(mut self, name: N, value: V) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Offer a subprotocol in `Sec-WebSocket-Protocol`. The one picked by the server is
+    /// reported in the `Connected` event.
+    pub fn protocol<S: Into<String>>(mut self, protocol: S) -> Self {
+        self.protocols.push(protocol.into());
+        self
+    }
+
+    /// Set the `Origin` header.
+    ///
+    /// The browser always sends the origin of the page so this is ignored on wasm.
+    pub fn origin<S: Into<String>>(mut self, origin: S) -> Self {
+        self.origin = Some(origin.into());
+        self
+    }
+
+    /// Keep reconnecting when the connection can't be opened or is lost, until it's closed with
+    /// `WebSocketSink::close` or the policy runs out of attempts.
+    pub fn reconnect(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect = Some(policy);
+        self
+    }
+
+    pub fn heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.heartbeat = Some(heartbeat);
+        self
+    }
+
+    /// Give up with `ConnectionFailed(Error::Timeout)` if the connection isn't open within
+    /// `timeout`, including the TCP connection and the opening handshake.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Report a `Timeout` and close the connection if nothing is received for `timeout`. Like a
+    /// `Heartbeat` but without sending anything to provoke a reply.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Limit how many messages can be waiting to be sent, at least 1. What happens when it's
+    /// full is set with `overflow_policy`. The queue is unbounded by default.
+    ///
+    /// Browsers queue messages themselves so this only applies on native.
+    pub fn send_queue_capacity(mut self, capacity: usize) -> Self {
+        self.send_queue_capacity = Some(capacity);
+        self
+    }
+
+    /// What `WebSocketSink::send` does when the send queue is full. It can be changed later with
+    /// `WebSocketSink::set_overflow_policy`.
+    pub fn overflow_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.overflow_policy = policy;
+        self
+    }
+
+    /// How long an open connection can go without receiving anything before it's dead.
+    pub(crate) fn idle_timeout(&self) -> Option<Duration> {
+        let heartbeat_timeout = self.heartbeat.as_ref().map(|heartbeat| heartbeat.timeout);
+        match (heartbeat_timeout, self.read_timeout) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_delay: Duration::from_millis(500),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(30),
+            jitter: 0.2,
+            max_attempts: None,
+        }
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+            fallback_message: String::from("ping"),
+        }
+    }
+}
+
+impl TlsConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trust a PEM encoded certificate in addition to the default roots, e.g. for a server
+    /// using a self-signed certificate.
+    pub fn root_certificate_pem<C: Into<Vec<u8>>>(mut self, pem: C) -> Self {
+        self.root_certificates.push(pem.into());
+        self
+    }
+
+    /// Don't check the server's certificate at all. Only for use in development.
+    pub fn danger_accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.accept_invalid_certs = accept;
+        self
+    }
+}
+
+impl ReconnectPolicy {
+    /// The delay before the given attempt (counting from 1), or `None` if we should give up.
+    /// `random` is a sample from [0, 1) used to apply the jitter.
+    pub(crate) fn delay(&self, attempt: u32, random: f64) -> Option<Duration> {
+        if let Some(max_attempts) = self.max_attempts {
+            if attempt > max_attempts {
+                return None;
+            }
+        }
+        // The fields are public, so anything that would make `from_secs_f64` or the timer panic
+        // is reined in here. `max` turns a NaN multiplier into 1.0, but `clamp` would pass a NaN
+        // through. `min` ignores the NaN from multiplying a zero delay by an infinite multiplier.
+        let max_delay = self.max_delay.min(MAX_RECONNECT_DELAY);
+        let multiplier = self.multiplier.max(1.0);
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let delay = (self.initial_delay.min(max_delay).as_secs_f64() * multiplier.powi(exponent))
+            .min(max_delay.as_secs_f64());
+        let jitter = if self.jitter.is_nan() {
+            0.0
+        } else {
+            self.jitter.clamp(0.0, 1.0)
+        };
+        let delay = (delay * (1.0 - jitter * random)).max(0.0);
+        Some(Duration::from_secs_f64(delay))
+    }
+}
+
+impl From<&str> for ConnectRequest {
+    fn from(url: &str) -> Self {
+        ConnectRequest::new(url)
+    }
+}
+
+impl From<String> for ConnectRequest {
+    fn from(url: String) -> Self {
+        ConnectRequest::new(url)
+    }
+}
+
+impl From<&String> for ConnectRequest {
+    fn from(url: &String) -> Self {
+        ConnectRequest::new(url.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ReconnectPolicy, MAX_RECONNECT_DELAY};
+    use std::time::Duration;
+
+    #[test]
+    fn reconnect_delay_backs_off() {
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(5),
+            jitter: 0.5,
+            max_attempts: Some(4),
+        };
+        assert_eq!(policy.delay(1, 0.0), Some(Duration::from_secs(1)));
+        assert_eq!(policy.delay(2, 0.0), Some(Duration::from_secs(2)));
+        assert_eq!(policy.delay(3, 0.0), Some(Duration::from_secs(4)));
+        assert_eq!(policy.delay(4, 0.0), Some(Duration::from_secs(5)));
+        assert_eq!(policy.delay(5, 0.0), None);
+        assert_eq!(policy.delay(2, 1.0), Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn reconnect_delay_ignores_bad_values() {
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            multiplier: -2.0,
+            max_delay: Duration::from_secs(5),
+            jitter: f64::NAN,
+            max_attempts: None,
+        };
+        assert_eq!(policy.delay(2, 0.5), Some(Duration::from_secs(1)));
+        let policy = ReconnectPolicy {
+            multiplier: f64::NAN,
+            jitter: 2.0,
+            ..policy
+        };
+        assert_eq!(policy.delay(3, 1.0), Some(Duration::from_secs(0)));
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::MAX,
+            multiplier: f64::INFINITY,
+            max_delay: Duration::MAX,
+            jitter: 0.0,
+            ..policy
+        };
+        assert_eq!(policy.delay(1, 0.5), Some(MAX_RECONNECT_DELAY));
+        assert_eq!(policy.delay(u32::MAX, 0.5), Some(MAX_RECONNECT_DELAY));
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_secs(0),
+            ..policy
+        };
+        assert_eq!(policy.delay(2, 0.5), Some(MAX_RECONNECT_DELAY));
+    }
+}
@@ -0,0 +1,375 @@
+//! The queue between a `WebSocketSink` and the task that writes to the connection, so that
+//! sending from the miniquad thread never has to wait for the network.
+
+use std::fmt::{self, Debug, Formatter};
+use std::sync::Arc;
+
+/// A message waiting to be sent. Handed back in `Error::SendQueueFull` when it can't be queued.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutgoingMessage {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl From<String> for OutgoingMessage {
+    fn from(msg: String) -> Self {
+        OutgoingMessage::Text(msg)
+    }
+}
+
+impl From<&str> for OutgoingMessage {
+    fn from(msg: &str) -> Self {
+        OutgoingMessage::Text(msg.into())
+    }
+}
+
+impl From<Vec<u8>> for OutgoingMessage {
+    fn from(data: Vec<u8>) -> Self {
+        OutgoingMessage::Binary(data)
+    }
+}
+
+impl From<&[u8]> for OutgoingMessage {
+    fn from(data: &[u8]) -> Self {
+        OutgoingMessage::Binary(data.into())
+    }
+}
+
+type KeyFn = Arc<dyn Fn(&OutgoingMessage) -> Option<u64> + Send + Sync>;
+
+/// What `WebSocketSink::send` does when the send queue is full.
+///
+/// Browsers queue messages themselves, without a limit, so on wasm sends are never refused and
+/// the policy has no effect.
+#[derive(Clone, Default)]
+pub enum OverflowPolicy {
+    /// Wait for there to be room. Don't use this from the miniquad thread, it will stall the
+    /// frame until the network catches up.
+    Block,
+    /// Hand the message back in `Error::SendQueueFull`. This is the default.
+    #[default]
+    Error,
+    /// Throw away the oldest queued message to make room.
+    DropOldest,
+    /// Throw away the message being sent, `send` still returns `Ok`.
+    DropNewest,
+    /// Messages given the same key by the function replace each other while they're waiting to
+    /// be sent, so only the latest is sent. This applies whether or not the queue is full. A new
+    /// key when the queue is full, or a message without a key, is handed back as for `Error`.
+    CoalesceByKey(KeyFn),
+}
+
+impl OverflowPolicy {
+    pub fn coalesce_by_key<F>(key: F) -> Self
+    where
+        F: Fn(&OutgoingMessage) -> Option<u64> + Send + Sync + 'static,
+    {
+        OverflowPolicy::CoalesceByKey(Arc::new(key))
+    }
+}
+
+impl Debug for OverflowPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            OverflowPolicy::Block => write!(f, "Block"),
+            OverflowPolicy::Error => write!(f, "Error"),
+            OverflowPolicy::DropOldest => write!(f, "DropOldest"),
+            OverflowPolicy::DropNewest => write!(f, "DropNewest"),
+            OverflowPolicy::CoalesceByKey(_) => write!(f, "CoalesceByKey(...)"),
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use self::native::SendQueue;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod native {
+    use super::{OutgoingMessage, OverflowPolicy};
+    use crate::error::{Error, Result};
+    use crate::event::ConnectionState;
+    use std::collections::VecDeque;
+    use std::sync::{Condvar, Mutex};
+    use tokio::sync::Notify;
+    use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+    use tokio_tungstenite::tungstenite::Message;
+
+    pub(crate) struct SendQueue {
+        state: Mutex<State>,
+        /// Wakes the connection task when a message is queued or the queue is closed.
+        notify: Notify,
+        /// Wakes senders blocked by `OverflowPolicy::Block` when there's room or it's closed.
+        not_full: Condvar,
+        /// The same for async senders.
+        not_full_async: Notify,
+    }
+
+    struct State {
+        messages: VecDeque<Queued>,
+        capacity: Option<usize>,
+        policy: OverflowPolicy,
+        /// Set once nothing more can be queued, by `close` or when the connection ends.
+        closed: bool,
+        /// Set when the connection ends.
+        ended: bool,
+        close_frame: Option<CloseFrame<'static>>,
+        /// The number of `WebSocketSink`s, the queue is closed when the last one is dropped.
+        sinks: usize,
+    }
+
+    struct Queued {
+        key: Option<u64>,
+        msg: OutgoingMessage,
+    }
+
+    impl State {
+        fn is_full(&self) -> bool {
+            match self.capacity {
+                Some(capacity) => self.messages.len() >= capacity,
+                None => false,
+            }
+        }
+
+        fn key(&self, msg: &OutgoingMessage) -> Option<u64> {
+            match &self.policy {
+                OverflowPolicy::CoalesceByKey(key) => key(msg),
+                _ => None,
+            }
+        }
+    }
+
+    impl SendQueue {
+        /// `capacity` of `None` means unbounded.
+        pub(crate) fn new(capacity: Option<usize>, policy: OverflowPolicy) -> Self {
+            SendQueue {
+                state: Mutex::new(State {
+                    messages: VecDeque::new(),
+                    capacity: capacity.map(|capacity| capacity.max(1)),
+                    policy,
+                    closed: false,
+                    ended: false,
+                    close_frame: None,
+                    sinks: 0,
+                }),
+                notify: Notify::new(),
+                not_full: Condvar::new(),
+                not_full_async: Notify::new(),
+            }
+        }
+
+        pub(crate) fn add_sink(&self) {
+            self.state.lock().unwrap().sinks += 1;
+        }
+
+        /// Close the queue normally if this was the last sink.
+        pub(crate) fn remove_sink(&self) {
+            let mut state = self.state.lock().unwrap();
+            state.sinks -= 1;
+            if state.sinks == 0 && !state.closed {
+                drop(state);
+                let _ = self.close(None);
+            }
+        }
+
+        /// Whether `close` has been called or the connection has ended.
+        pub(crate) fn is_closed(&self) -> bool {
+            self.state.lock().unwrap().closed
+        }
+
+        pub(crate) fn state(&self) -> ConnectionState {
+            let state = self.state.lock().unwrap();
+            if state.ended {
+                ConnectionState::Closed
+            } else if state.closed {
+                ConnectionState::Closing
+            } else {
+                ConnectionState::Open
+            }
+        }
+
+        pub(crate) fn set_policy(&self, policy: OverflowPolicy) {
+            self.state.lock().unwrap().policy = policy;
+        }
+
+        /// Queue a message following the overflow policy.
+        pub(crate) fn push(&self, msg: OutgoingMessage) -> Result<()> {
+            self.push_inner(msg, true)
+        }
+
+        /// Queue a message following the overflow policy, except that it never blocks. The
+        /// message is handed back instead.
+        pub(crate) fn try_push(&self, msg: OutgoingMessage) -> Result<()> {
+            self.push_inner(msg, false)
+        }
+
+        /// Queue a message following the overflow policy, waiting asynchronously for `Block`.
+        pub(crate) async fn push_async(&self, mut msg: OutgoingMessage) -> Result<()> {
+            loop {
+                match self.push_inner(msg, false) {
+                    Err(Error::SendQueueFull(returned)) if self.blocks() => {
+                        msg = returned;
+                        self.not_full_async.notified().await;
+                    }
+                    result => return result,
+                }
+            }
+        }
+
+        fn blocks(&self) -> bool {
+            matches!(self.state.lock().unwrap().policy, OverflowPolicy::Block)
+        }
+
+        fn push_inner(&self, msg: OutgoingMessage, can_block: bool) -> Result<()> {
+            let mut state = self.state.lock().unwrap();
+            let key = state.key(&msg);
+            loop {
+                if state.closed {
+                    return Err(Error::AlreadyClosed);
+                }
+                if let Some(key) = key {
+                    let queued = state
+                        .messages
+                        .iter_mut()
+                        .find(|queued| queued.key == Some(key));
+                    if let Some(queued) = queued {
+                        queued.msg = msg;
+                        return Ok(());
+                    }
+                }
+                if !state.is_full() {
+                    break;
+                }
+                match state.policy {
+                    OverflowPolicy::Block if can_block => {
+                        state = self.not_full.wait(state).unwrap();
+                    }
+                    OverflowPolicy::DropOldest => {
+                        state.messages.pop_front();
+                        break;
+                    }
+                    OverflowPolicy::DropNewest => return Ok(()),
+                    _ => return Err(Error::SendQueueFull(msg)),
+                }
+            }
+            state.messages.push_back(Queued { key, msg });
+            drop(state);
+            self.notify.notify();
+            Ok(())
+        }
+
+        /// Stop taking messages and send a close frame once everything already queued has gone.
+        pub(crate) fn close(&self, frame: Option<CloseFrame<'static>>) -> Result<()> {
+            let mut state = self.state.lock().unwrap();
+            if state.closed {
+                return Err(Error::AlreadyClosed);
+            }
+            state.closed = true;
+            state.close_frame = frame;
+            drop(state);
+            self.notify.notify();
+            self.not_full.notify_all();
+            self.not_full_async.notify();
+            Ok(())
+        }
+
+        /// Called when the connection has ended, anything still queued is dropped.
+        pub(crate) fn finish(&self) {
+            let mut state = self.state.lock().unwrap();
+            state.closed = true;
+            state.ended = true;
+            state.messages.clear();
+            drop(state);
+            self.not_full.notify_all();
+            self.not_full_async.notify();
+        }
+
+        /// Wait for the next message to send. After the queue is closed and emptied this gives
+        /// the close message, after which it shouldn't be called again.
+        pub(crate) async fn next(&self) -> Message {
+            loop {
+                {
+                    let mut state = self.state.lock().unwrap();
+                    if let Some(queued) = state.messages.pop_front() {
+                        self.not_full.notify_all();
+                        self.not_full_async.notify();
+                        return queued.msg.into();
+                    }
+                    if state.closed {
+                        return Message::Close(state.close_frame.take());
+                    }
+                }
+                self.notify.notified().await;
+            }
+        }
+    }
+
+    impl From<OutgoingMessage> for Message {
+        fn from(msg: OutgoingMessage) -> Self {
+            match msg {
+                OutgoingMessage::Text(text) => Message::Text(text),
+                OutgoingMessage::Binary(data) => Message::Binary(data),
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::{OutgoingMessage, OverflowPolicy, SendQueue};
+    use crate::error::Error;
+
+    fn text(s: &str) -> OutgoingMessage {
+        OutgoingMessage::Text(s.into())
+    }
+
+    #[test]
+    fn full_queue_hands_message_back() {
+        let queue = SendQueue::new(Some(1), OverflowPolicy::Error);
+        queue.try_push(text("a")).unwrap();
+        match queue.try_push(text("b")) {
+            Err(Error::SendQueueFull(msg)) => assert_eq!(msg, text("b")),
+            other => panic!("unexpected {:?}", other),
+        }
+        queue.close(None).unwrap();
+        assert!(matches!(
+            queue.try_push(text("c")),
+            Err(Error::AlreadyClosed)
+        ));
+    }
+
+    #[test]
+    fn overflow_policies() {
+        let drain = |queue: SendQueue| {
+            queue.close(None).unwrap();
+            let mut sent = Vec::new();
+            loop {
+                match futures_util::FutureExt::now_or_never(queue.next()).unwrap() {
+                    tokio_tungstenite::tungstenite::Message::Text(s) => sent.push(s),
+                    _ => return sent,
+                }
+            }
+        };
+
+        let queue = SendQueue::new(Some(2), OverflowPolicy::DropOldest);
+        for s in &["a", "b", "c"] {
+            queue.push(text(s)).unwrap();
+        }
+        assert_eq!(drain(queue), vec!["b", "c"]);
+
+        let queue = SendQueue::new(Some(2), OverflowPolicy::DropNewest);
+        for s in &["a", "b", "c"] {
+            queue.push(text(s)).unwrap();
+        }
+        assert_eq!(drain(queue), vec!["a", "b"]);
+
+        let key = |msg: &OutgoingMessage| match msg {
+            OutgoingMessage::Text(s) => s.bytes().next().map(u64::from),
+            OutgoingMessage::Binary(_) => None,
+        };
+        let queue = SendQueue::new(None, OverflowPolicy::coalesce_by_key(key));
+        for s in &["a1", "b1", "a2", "a3", "b2"] {
+            queue.push(text(s)).unwrap();
+        }
+        assert_eq!(drain(queue), vec!["a3", "b2"]);
+    }
+}
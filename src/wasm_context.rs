@@ -0,0 +1,345 @@
+//! The context and connection registry shared by the wasm backends. Everything runs on the
+//! browser's one thread so the connections are kept in an `Rc<RefCell<_>>` rather than behind a
+//! lock. The backend supplies the `WebSocketSink` and `connect`, which drives the browser socket
+//! and keeps its entry up to date through a `Registration`.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use crate::error::{Error, Result};
+use crate::event_sink::{EventSink, PostBox};
+use crate::imp::{connect, WebSocketSink};
+use crate::{CloseCode, ConnectRequest, ConnectionState, OutgoingMessage, WebSocketEvent};
+
+pub struct WebSocketContext<EventType> {
+    post_box: PostBox<EventType>,
+    events: Option<mpsc::Receiver<EventType>>,
+    connections: Rc<RefCell<Connections>>,
+}
+
+/// The connections started from a context, so that they can be looked up by id.
+#[derive(Default)]
+struct Connections {
+    live: HashMap<u32, Connection>,
+}
+
+/// Keys for `Connections`, which are unique across contexts as the JS side of `wasm_imp` uses
+/// them to find the socket to cancel.
+static NEXT_KEY: AtomicU32 = AtomicU32::new(0);
+
+/// Returned by `start_connect` to cancel the connection.
+#[derive(Clone)]
+pub struct ConnectHandle {
+    connections: Rc<RefCell<Connections>>,
+    key: u32,
+}
+
+struct Connection {
+    /// The `WebSocketId`, which can be of any type as `start_connect` is generic over it.
+    id: Box<dyn Any>,
+    /// The sink once it's opened, `None` while connecting or waiting to reconnect.
+    sink: Option<WebSocketSink>,
+    /// Stops connecting or waiting to reconnect, from the backend's `connect`. `None` until
+    /// that's returned.
+    cancel: Option<Rc<dyn Fn()>>,
+}
+
+impl Connection {
+    fn state(&self) -> ConnectionState {
+        match &self.sink {
+            Some(sink) => sink.state(),
+            None => ConnectionState::Connecting,
+        }
+    }
+
+    fn stopper(&self) -> Stopper {
+        Stopper {
+            sink: self.sink.clone(),
+            cancel: self.cancel.clone(),
+        }
+    }
+}
+
+/// Stops a connection without holding a borrow of `Connections`, as cancelling removes the
+/// entry.
+struct Stopper {
+    sink: Option<WebSocketSink>,
+    cancel: Option<Rc<dyn Fn()>>,
+}
+
+impl Stopper {
+    /// Close the connection normally if it's open, otherwise cancel it, see
+    /// `ConnectHandle::cancel`.
+    fn stop(self) {
+        match (self.sink, self.cancel) {
+            // The close is reported as usual
+            (Some(mut sink), _) => {
+                let _ = sink.close(CloseCode::Normal, String::new());
+            }
+            (None, Some(cancel)) => cancel(),
+            (None, None) => {}
+        }
+    }
+}
+
+impl Connections {
+    /// The sink of the first open connection with `id`, in the order they were started.
+    fn open<WebSocketId: PartialEq + 'static>(&self, id: &WebSocketId) -> Option<WebSocketSink> {
+        self.live
+            .iter()
+            .filter(|(_, connection)| connection.id.downcast_ref() == Some(id))
+            .filter(|(_, connection)| connection.state() == ConnectionState::Open)
+            .min_by_key(|(key, _)| **key)
+            .and_then(|(_, connection)| connection.sink.clone())
+    }
+}
+
+/// A connection's entry in its context, handed to the backend's `connect`.
+pub(crate) struct Registration {
+    connections: Rc<RefCell<Connections>>,
+    /// Unique across contexts, see `NEXT_KEY`.
+    pub(crate) key: u32,
+}
+
+impl Registration {
+    /// Record the sink while the connection is open, `None` while reconnecting.
+    pub(crate) fn set_sink(&self, sink: Option<WebSocketSink>) {
+        if let Some(connection) = self.connections.borrow_mut().live.get_mut(&self.key) {
+            connection.sink = sink;
+        }
+    }
+
+    /// Called once the connection has ended for good.
+    pub(crate) fn unregister(&self) {
+        self.connections.borrow_mut().live.remove(&self.key);
+    }
+}
+
+/// Events are delivered to `event_sink`.
+pub fn init<WebSocketId, EventType, S>(event_sink: S) -> Result<WebSocketContext<EventType>>
+where
+    EventType: Send + From<WebSocketEvent<WebSocketId>>,
+    WebSocketId: Clone,
+    S: EventSink<EventType> + Clone + 'static,
+{
+    Ok(WebSocketContext {
+        post_box: PostBox::new(event_sink),
+        events: None,
+        connections: Default::default(),
+    })
+}
+
+/// Like `init` but events are kept in a queue in the context, to be collected with
+/// `WebSocketContext::poll_events` or `drain`, e.g. from `EventHandler::update`. For when
+/// there's no way to post custom events to the event loop.
+pub fn init_polling<WebSocketId, EventType>() -> Result<WebSocketContext<EventType>>
+where
+    EventType: Send + From<WebSocketEvent<WebSocketId>> + 'static,
+    WebSocketId: Clone,
+{
+    let (tx, rx) = mpsc::channel();
+    Ok(WebSocketContext {
+        post_box: PostBox::new(tx),
+        events: Some(rx),
+        connections: Default::default(),
+    })
+}
+
+/// Register a connection in `connections` and have the backend start connecting.
+fn start<WebSocketId, EventType>(
+    connections: &Rc<RefCell<Connections>>,
+    post_box: PostBox<EventType>,
+    id: WebSocketId,
+    request: ConnectRequest,
+) -> u32
+where
+    EventType: Send + From<WebSocketEvent<WebSocketId>> + 'static,
+    WebSocketId: Send + Clone + 'static,
+{
+    let key = NEXT_KEY.fetch_add(1, Ordering::Relaxed);
+    connections.borrow_mut().live.insert(
+        key,
+        Connection {
+            id: Box::new(id.clone()),
+            sink: None,
+            cancel: None,
+        },
+    );
+    let registration = Registration {
+        connections: connections.clone(),
+        key,
+    };
+    let cancel = connect(id, request, post_box, registration);
+    // It's gone already if the backend failed straight away
+    if let Some(connection) = connections.borrow_mut().live.get_mut(&key) {
+        connection.cancel = Some(cancel);
+    }
+    key
+}
+
+/// Start connecting without a context, for the async API.
+pub(crate) fn spawn_connect<WebSocketId, EventType>(
+    id: WebSocketId,
+    request: ConnectRequest,
+    post_box: PostBox<EventType>,
+) -> Result<()>
+where
+    EventType: Send + From<WebSocketEvent<WebSocketId>> + 'static,
+    WebSocketId: Send + Clone + 'static,
+{
+    start(&Default::default(), post_box, id, request);
+    Ok(())
+}
+
+impl<EventType> WebSocketContext<EventType> {
+    /// Start connecting, the result is reported with a `Connected` or `ConnectionFailed` event.
+    ///
+    /// If the request has a `ReconnectPolicy` then failed attempts, and connections that are
+    /// lost, are retried with `Reconnecting` events reported before each new attempt.
+    ///
+    /// Headers and origin set on the request are ignored, browsers don't allow setting them.
+    ///
+    /// The returned handle can be used to cancel connecting.
+    pub fn start_connect<WebSocketId, R>(
+        &mut self,
+        id: WebSocketId,
+        request: R,
+    ) -> Result<ConnectHandle>
+    where
+        EventType: Send + From<WebSocketEvent<WebSocketId>> + 'static,
+        WebSocketId: Send + Clone + 'static,
+        R: Into<ConnectRequest>,
+    {
+        let key = start(&self.connections, self.post_box.clone(), id, request.into());
+        Ok(ConnectHandle {
+            connections: self.connections.clone(),
+            key,
+        })
+    }
+
+    /// The events queued since the last call, for a context made with `init_polling`. This
+    /// doesn't wait for more. Contexts made with `init` deliver their events to the sink so
+    /// there's never anything here.
+    pub fn poll_events(&mut self) -> impl Iterator<Item = EventType> + '_ {
+        self.events.iter().flat_map(|events| events.try_iter())
+    }
+
+    /// Take all of the queued events, see `poll_events`.
+    pub fn drain(&mut self) -> Vec<EventType> {
+        self.poll_events().collect()
+    }
+
+    /// Send a message on the open connection with `id`. Fails with `Error::NotConnected` if
+    /// there isn't one, e.g. while it's still connecting. If more than one connection has the
+    /// id the one started first is used.
+    pub fn send_to<WebSocketId, M>(&mut self, id: &WebSocketId, msg: M) -> Result<()>
+    where
+        WebSocketId: PartialEq + 'static,
+        M: Into<OutgoingMessage>,
+    {
+        let sink = self.connections.borrow().open(id);
+        sink.ok_or(Error::NotConnected)?.send_message(msg.into())
+    }
+
+    /// Send a message on every open connection, whatever the type of its id. Returns how many
+    /// it was sent on.
+    pub fn broadcast<M: Into<OutgoingMessage>>(&mut self, msg: M) -> usize {
+        let msg = msg.into();
+        let sinks: Vec<_> = self
+            .connections
+            .borrow()
+            .live
+            .values()
+            .filter(|connection| connection.state() == ConnectionState::Open)
+            .filter_map(|connection| connection.sink.clone())
+            .collect();
+        sinks
+            .into_iter()
+            .filter(|sink| sink.clone().send_message(msg.clone()).is_ok())
+            .count()
+    }
+
+    /// Start the closing handshake on the open connection with `id`, see `WebSocketSink::close`.
+    pub fn close<WebSocketId>(
+        &mut self,
+        id: &WebSocketId,
+        code: CloseCode,
+        reason: String,
+    ) -> Result<()>
+    where
+        WebSocketId: PartialEq + 'static,
+    {
+        let sink = self.connections.borrow().open(id);
+        sink.ok_or(Error::NotConnected)?.close(code, reason)
+    }
+
+    /// Whether there's an open connection with `id`.
+    pub fn is_open<WebSocketId: PartialEq + 'static>(&self, id: &WebSocketId) -> bool {
+        self.connections.borrow().open(id).is_some()
+    }
+
+    /// The connections with ids of type `WebSocketId`, in the order they were started. Ones
+    /// that have closed for good, or failed, aren't included.
+    pub fn connections<WebSocketId: Clone + 'static>(
+        &self,
+    ) -> impl Iterator<Item = (WebSocketId, ConnectionState)> {
+        let connections = self.connections.borrow();
+        let mut states: Vec<_> = connections
+            .live
+            .iter()
+            .filter_map(|(key, connection)| {
+                let id = connection.id.downcast_ref::<WebSocketId>()?;
+                Some((*key, id.clone(), connection.state()))
+            })
+            .collect();
+        states.sort_by_key(|(key, _, _)| *key);
+        states.into_iter().map(|(_, id, state)| (id, state))
+    }
+
+    /// Close all of the open connections, stopping any reconnections, and cancel the ones that
+    /// are still connecting. Returns whether they've all ended.
+    ///
+    /// The browser can't be waited on, so unlike native this doesn't block and `timeout` is
+    /// ignored. The closing handshakes carry on in the background and are reported as usual.
+    /// Browsers don't allow `CloseCode::GoingAway`, so the connections are closed with
+    /// `CloseCode::Normal`.
+    pub fn shutdown(mut self, _timeout: Duration) -> bool {
+        self.close_all();
+        self.connections.borrow().live.is_empty()
+    }
+
+    fn close_all(&mut self) {
+        let stoppers: Vec<_> = self
+            .connections
+            .borrow()
+            .live
+            .values()
+            .map(Connection::stopper)
+            .collect();
+        stoppers.into_iter().for_each(Stopper::stop);
+    }
+}
+
+impl<EventType> Drop for WebSocketContext<EventType> {
+    /// Like `shutdown`, the connections started from the context are closed or cancelled.
+    fn drop(&mut self) {
+        self.close_all();
+    }
+}
+
+impl ConnectHandle {
+    /// Stop connecting, or waiting to reconnect, and report `Cancelled`. If the connection is
+    /// already open it's closed normally instead. Does nothing once it's closed for good.
+    pub fn cancel(&self) {
+        let stopper = match self.connections.borrow().live.get(&self.key) {
+            Some(connection) => connection.stopper(),
+            None => return,
+        };
+        stopper.stop();
+    }
+}
@@ -1,90 +1,285 @@
-use std::borrow::Cow;
-use std::ffi::{c_void, CString};
+use std::ffi::c_void;
+use std::rc::Rc;
+use std::time::Duration;
 
-use miniquad::CustomEventPostBox;
+use crate::error::{check_browser_close_code, check_close_reason, Error, FailurePhase, Result};
+use crate::event_sink::PostBox;
+pub(crate) use crate::wasm_context::spawn_connect;
+use crate::wasm_context::Registration;
+pub use crate::wasm_context::{init, init_polling, ConnectHandle, WebSocketContext};
+use crate::{
+    CloseCode, ConnectRequest, ConnectionState, Heartbeat, OutgoingMessage, OverflowPolicy,
+    ReconnectPolicy, WebSocketEvent,
+};
 
-use crate::error::{Error, Result};
-use crate::WebSocketEvent;
-
-pub struct WebSocketContext<EventType> {
-    post_box: CustomEventPostBox<EventType>,
-}
+/// Sends messages on a connection.
+#[derive(Clone)]
 pub struct WebSocketSink {
     inner_id: u32,
 }
 
 extern "C" {
-    fn websocket_start_connect(cb_data_ptr: *const c_void, url_ptr: *const i8, url_len: u32);
-    fn websocket_send(inner_id: u32, msg_ptr: *const i8, msg_len: u32);
+    fn websocket_start_connect(
+        cb_data_ptr: *const c_void,
+        key: u32,
+        url_ptr: *const u8,
+        url_len: u32,
+        protocols_ptr: *const u8,
+        protocols_len: u32,
+        connect_timeout_ms: u32,
+        heartbeat_interval_ms: u32,
+        heartbeat_msg_ptr: *const u8,
+        heartbeat_msg_len: u32,
+        idle_timeout_ms: u32,
+        delay_ms: u32,
+    );
+    fn websocket_cancel_connect(key: u32);
+    fn websocket_random() -> f64;
+    fn websocket_send(inner_id: u32, msg_ptr: *const u8, msg_len: u32);
+    fn websocket_send_binary(inner_id: u32, data_ptr: *const u8, data_len: u32);
+    fn websocket_ready_state(inner_id: u32) -> u32;
+    fn websocket_close(inner_id: u32, code: u32, reason_ptr: *const u8, reason_len: u32);
+}
+
+/// The state shared by the callbacks. It's moved from one set of callbacks to the next, and
+/// `id` and `post_box` are freed by `free` once the connection has ended for good, so it mustn't
+/// be cloned.
+struct WebSocket {
+    id: *mut c_void,
+    post_box: *mut c_void,
+    url: String,
+    // Subprotocol names can't contain commas so we can pass them all in one string
+    protocols: String,
+    heartbeat: Option<Heartbeat>,
+    heartbeat_message: String,
+    connect_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    reconnect: Option<ReconnectPolicy>,
+    attempt: u32,
+    connected: bool,
+    registration: Registration,
 }
 
-pub fn init<WebSocketId, EventType>(
-    post_box: CustomEventPostBox<EventType>,
-) -> Result<WebSocketContext<EventType>>
+impl WebSocket {
+    fn browser_error(&self, phase: FailurePhase, code: Option<u16>) -> Error {
+        Error::Browser {
+            phase,
+            url: self.url.clone(),
+            code,
+        }
+    }
+}
+
+fn connecting_cbs<WebSocketId, EventType>(data: WebSocket) -> Box<ConnectingCbs>
 where
-    EventType: Send + From<WebSocketEvent<WebSocketId>>,
-    WebSocketId: Clone,
+    EventType: Send + From<WebSocketEvent<WebSocketId>> + 'static,
+    WebSocketId: Send + Clone + 'static,
 {
-    Ok(WebSocketContext { post_box })
+    Box::new(ConnectingCbs {
+        data,
+        on_open: on_open_::<WebSocketId, EventType>,
+        on_connection_failed: connection_failed_::<WebSocketId, EventType>,
+        on_cancelled: cancelled_::<WebSocketId, EventType>,
+    })
 }
 
-#[derive(Clone)]
-struct WebSocket {
-    id: *mut c_void,
-    post_box: *mut c_void,
+unsafe fn start_attempt(cbs: Box<ConnectingCbs>, delay: Duration) {
+    let url_ptr = cbs.data.url.as_ptr();
+    let url_len = cbs.data.url.len() as u32;
+    let protocols_ptr = cbs.data.protocols.as_ptr();
+    let protocols_len = cbs.data.protocols.len() as u32;
+    // 0 tells the JS side there's no heartbeat or timeout
+    let heartbeat_interval_ms = match &cbs.data.heartbeat {
+        Some(heartbeat) => millis(heartbeat.interval).max(1),
+        None => 0,
+    };
+    let connect_timeout_ms = timeout_ms(cbs.data.connect_timeout);
+    let idle_timeout_ms = timeout_ms(cbs.data.idle_timeout);
+    let heartbeat_msg_ptr = cbs.data.heartbeat_message.as_ptr();
+    let heartbeat_msg_len = cbs.data.heartbeat_message.len() as u32;
+    let key = cbs.data.registration.key;
+    websocket_start_connect(
+        Box::into_raw(cbs) as _,
+        key,
+        url_ptr,
+        url_len,
+        protocols_ptr,
+        protocols_len,
+        connect_timeout_ms,
+        heartbeat_interval_ms,
+        heartbeat_msg_ptr,
+        heartbeat_msg_len,
+        idle_timeout_ms,
+        millis(delay),
+    );
+}
+
+/// Clamped to what `setTimeout` accepts, it fires straight away for anything longer.
+fn millis(duration: Duration) -> u32 {
+    duration.as_millis().min(i32::MAX as u128) as u32
+}
+
+fn timeout_ms(timeout: Option<Duration>) -> u32 {
+    timeout.map_or(0, |timeout| millis(timeout).max(1))
+}
+
+/// Move on to the next attempt, returns the delay before it or `None` if we should give up.
+unsafe fn reconnect_delay(data: &mut WebSocket) -> Option<Duration> {
+    data.attempt += 1;
+    let attempt = data.attempt;
+    data.reconnect
+        .as_ref()
+        .and_then(|policy| policy.delay(attempt, websocket_random()))
+}
+
+/// Allocate a buffer for the JS side to copy a message or string into. Ownership comes back with
+/// `bytes_from_js`. A boxed slice is used so that the capacity is exactly `len`.
+#[no_mangle]
+pub extern "C" fn websocket_alloc(len: usize) -> *mut u8 {
+    Box::into_raw(vec![0u8; len].into_boxed_slice()) as *mut u8
+}
+
+/// Take back a buffer from `websocket_alloc`, `len` has to be the length it was allocated with.
+unsafe fn bytes_from_js(ptr: *mut u8, len: usize) -> Vec<u8> {
+    Box::from_raw(std::ptr::slice_from_raw_parts_mut(ptr, len)).into_vec()
+}
+
+/// The JS side encodes with `TextEncoder` so this should always be valid, but it's checked
+/// rather than trusted.
+unsafe fn string_from_js(ptr: *mut u8, len: usize) -> Result<String> {
+    Ok(String::from_utf8(bytes_from_js(ptr, len))?)
+}
+
+/// For strings that are only informative, like the close reason, where it's better to have
+/// something than an error.
+unsafe fn string_from_js_lossy(ptr: *mut u8, len: usize) -> String {
+    String::from_utf8(bytes_from_js(ptr, len))
+        .unwrap_or_else(|err| String::from_utf8_lossy(err.as_bytes()).into_owned())
 }
 
 struct ConnectingCbs {
     data: WebSocket,
-    on_open: unsafe fn(data: WebSocket, inner_id: u32) -> Box<RunningCbs>,
-    on_connection_failed: unsafe fn(data: WebSocket),
+    on_open: unsafe fn(
+        data: WebSocket,
+        inner_id: u32,
+        protocol_ptr: *mut u8,
+        protocol_len: usize,
+    ) -> Box<RunningCbs>,
+    on_connection_failed: unsafe fn(data: WebSocket, timed_out: bool, code: u32),
+    on_cancelled: unsafe fn(data: WebSocket),
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn on_open(data: *mut c_void, inner_id: u32) -> *mut c_void {
+pub unsafe extern "C" fn on_open(
+    data: *mut c_void,
+    inner_id: u32,
+    protocol_ptr: *mut u8,
+    protocol_len: usize,
+) -> *mut c_void {
     let ConnectingCbs { data, on_open, .. } = *Box::from_raw(data as *mut ConnectingCbs);
-    let running_cbs = on_open(data, inner_id);
+    let running_cbs = on_open(data, inner_id, protocol_ptr, protocol_len);
     Box::into_raw(running_cbs) as *mut _
 }
 
-unsafe fn on_open_<WebSocketId, EventType>(data: WebSocket, inner_id: u32) -> Box<RunningCbs>
+unsafe fn on_open_<WebSocketId, EventType>(
+    mut data: WebSocket,
+    inner_id: u32,
+    protocol_ptr: *mut u8,
+    protocol_len: usize,
+) -> Box<RunningCbs>
 where
     EventType: Send + From<WebSocketEvent<WebSocketId>> + 'static,
     WebSocketId: Send + Clone + 'static,
 {
     let id = (*(data.id as *mut WebSocketId)).clone();
-    let post_box = &*(data.post_box as *const CustomEventPostBox<EventType>);
-    post_box.post(WebSocketEvent::connected(id, WebSocketSink { inner_id }));
+    let post_box = &*(data.post_box as *const PostBox<EventType>);
+    let protocol = string_from_js_lossy(protocol_ptr, protocol_len);
+    // The browser reports an empty string when no subprotocol was negotiated
+    let protocol = if protocol.is_empty() {
+        None
+    } else {
+        Some(protocol)
+    };
+    let sink = WebSocketSink { inner_id };
+    data.registration.set_sink(Some(sink.clone()));
+    if data.connected {
+        post_box.post(WebSocketEvent::reconnected(id, sink));
+    } else {
+        post_box.post(WebSocketEvent::connected(id, sink, protocol));
+    }
+    data.connected = true;
+    data.attempt = 0;
     Box::new(RunningCbs {
         data,
         on_message: on_message_::<WebSocketId, EventType>,
+        on_binary_message: on_binary_message_::<WebSocketId, EventType>,
         on_close: on_close_::<WebSocketId, EventType>,
         on_error: on_error_::<WebSocketId, EventType>,
+        on_timeout: on_timeout_::<WebSocketId, EventType>,
     })
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn on_connection_failed(data: *mut c_void) {
+pub unsafe extern "C" fn on_connection_failed(data: *mut c_void, timed_out: bool, code: u32) {
     let ConnectingCbs {
         data,
         on_connection_failed,
         ..
     } = *Box::from_raw(data as *mut ConnectingCbs);
-    on_connection_failed(data);
+    on_connection_failed(data, timed_out, code);
+}
+
+unsafe fn connection_failed_<WebSocketId, EventType>(
+    mut data: WebSocket,
+    timed_out: bool,
+    code: u32,
+) where
+    EventType: Send + From<WebSocketEvent<WebSocketId>> + 'static,
+    WebSocketId: Send + Clone + 'static,
+{
+    if let Some(delay) = reconnect_delay(&mut data) {
+        let id = (*(data.id as *mut WebSocketId)).clone();
+        let post_box = &*(data.post_box as *const PostBox<EventType>);
+        post_box.post(WebSocketEvent::reconnecting(id, data.attempt, delay));
+        start_attempt(connecting_cbs::<WebSocketId, EventType>(data), delay);
+        return;
+    }
+    let err = if timed_out {
+        Error::Timeout
+    } else {
+        // 0 when the browser refused to start connecting, so there was no close event
+        let code = Some(code as u16).filter(|code| *code != 0);
+        data.browser_error(FailurePhase::Connecting, code)
+    };
+    let (id, post_box) = free::<WebSocketId, EventType>(data);
+    post_box.post(WebSocketEvent::connection_failed(id, err));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn on_cancelled(data: *mut c_void) {
+    let ConnectingCbs {
+        data, on_cancelled, ..
+    } = *Box::from_raw(data as *mut ConnectingCbs);
+    on_cancelled(data);
 }
 
-unsafe fn connection_failed_<WebSocketId, EventType>(data: WebSocket)
+unsafe fn cancelled_<WebSocketId, EventType>(data: WebSocket)
 where
     EventType: Send + From<WebSocketEvent<WebSocketId>> + 'static,
     WebSocketId: Send + Clone + 'static,
 {
-    let id = *Box::from_raw(data.id as *mut WebSocketId);
-    let post_box = Box::from_raw(data.post_box as *mut CustomEventPostBox<EventType>);
-    post_box.post(WebSocketEvent::connection_failed(
-        id,
-        // TODO: proper error
-        Error::ConnectionClosed,
-    ));
+    let (id, post_box) = free::<WebSocketId, EventType>(data);
+    post_box.post(WebSocketEvent::cancelled(id));
+}
+
+/// Called once the connection has ended for good, handing back the id and post box for the
+/// last event.
+unsafe fn free<WebSocketId, EventType>(data: WebSocket) -> (WebSocketId, Box<PostBox<EventType>>) {
+    data.registration.unregister();
+    (
+        *Box::from_raw(data.id as *mut WebSocketId),
+        Box::from_raw(data.post_box as *mut PostBox<EventType>),
+    )
 }
 
 struct RunningCbs {
@@ -93,14 +288,17 @@ struct RunningCbs {
     //  native/sapp-wasm/js/gl.js:1110
     //  native/sapp-wasm/src/lib.rs:356
     on_message: unsafe fn(data: &mut WebSocket, msg_ptr: *mut u8, msg_len: usize),
+    on_binary_message: unsafe fn(data: &mut WebSocket, data_ptr: *mut u8, data_len: usize),
     on_close: unsafe fn(
-        data: &mut WebSocket,
+        data: WebSocket,
         code: u32,
         reason_ptr: *mut u8,
         reason_len: usize,
         was_clean: bool,
+        closed_locally: bool,
     ),
     on_error: unsafe fn(data: &mut WebSocket),
+    on_timeout: unsafe fn(data: WebSocket),
 }
 
 #[no_mangle]
@@ -118,9 +316,31 @@ unsafe fn on_message_<WebSocketId, EventType>(
     WebSocketId: Send + Clone + 'static,
 {
     let id = (*(data.id as *mut WebSocketId)).clone();
-    let post_box = &*(data.post_box as *const CustomEventPostBox<EventType>);
-    let msg = String::from_raw_parts(msg_ptr, msg_len, msg_len);
-    post_box.post(WebSocketEvent::message(id, msg));
+    let post_box = &*(data.post_box as *const PostBox<EventType>);
+    match string_from_js(msg_ptr, msg_len) {
+        Ok(msg) => post_box.post(WebSocketEvent::message(id, msg)),
+        Err(err) => post_box.post(WebSocketEvent::error(id, err)),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn on_binary_message(data: *mut c_void, data_ptr: *mut u8, data_len: usize) {
+    let cbs = &mut *(data as *mut RunningCbs);
+    (cbs.on_binary_message)(&mut cbs.data, data_ptr, data_len);
+}
+
+unsafe fn on_binary_message_<WebSocketId, EventType>(
+    data: &mut WebSocket,
+    data_ptr: *mut u8,
+    data_len: usize,
+) where
+    EventType: Send + From<WebSocketEvent<WebSocketId>> + 'static,
+    WebSocketId: Send + Clone + 'static,
+{
+    let id = (*(data.id as *mut WebSocketId)).clone();
+    let post_box = &*(data.post_box as *const PostBox<EventType>);
+    let bytes = bytes_from_js(data_ptr, data_len);
+    post_box.post(WebSocketEvent::binary(id, bytes));
 }
 
 #[no_mangle]
@@ -130,74 +350,216 @@ pub unsafe extern "C" fn on_close(
     reason_ptr: *mut u8,
     reason_len: usize,
     was_clean: bool,
+    closed_locally: bool,
 ) {
-    let cbs = &mut *(data as *mut RunningCbs);
-    (cbs.on_close)(&mut cbs.data, code, reason_ptr, reason_len, was_clean);
+    // This is the last callback for the socket, so the callbacks are freed
+    let RunningCbs { data, on_close, .. } = *Box::from_raw(data as *mut RunningCbs);
+    on_close(
+        data,
+        code,
+        reason_ptr,
+        reason_len,
+        was_clean,
+        closed_locally,
+    );
 }
 
 unsafe fn on_close_<WebSocketId, EventType>(
-    data: &mut WebSocket,
+    mut data: WebSocket,
     code: u32,
     reason_ptr: *mut u8,
     reason_len: usize,
-    _was_clean: bool,
+    was_clean: bool,
+    closed_locally: bool,
 ) where
     EventType: Send + From<WebSocketEvent<WebSocketId>> + 'static,
     WebSocketId: Send + Clone + 'static,
 {
     let id = (*(data.id as *mut WebSocketId)).clone();
-    let post_box = &*(data.post_box as *const CustomEventPostBox<EventType>);
-    let reason = String::from_raw_parts(reason_ptr, reason_len, reason_len);
-    post_box.post(WebSocketEvent::close_msg(id, code, reason));
+    let post_box = &*(data.post_box as *const PostBox<EventType>);
+    let reason = string_from_js_lossy(reason_ptr, reason_len);
+    post_box.post(WebSocketEvent::close_msg(
+        id.clone(),
+        (code as u16).into(),
+        reason,
+        was_clean,
+    ));
+    post_box.post(WebSocketEvent::connection_closed(id.clone()));
+    if !closed_locally {
+        if let Some(delay) = reconnect_delay(&mut data) {
+            data.registration.set_sink(None);
+            post_box.post(WebSocketEvent::reconnecting(id, data.attempt, delay));
+            start_attempt(connecting_cbs::<WebSocketId, EventType>(data), delay);
+            return;
+        }
+    }
+    free::<WebSocketId, EventType>(data);
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn on_error(data: *mut c_void) {}
+pub unsafe extern "C" fn on_error(data: *mut c_void) {
+    let cbs = &mut *(data as *mut RunningCbs);
+    (cbs.on_error)(&mut cbs.data);
+}
 
+/// The browser follows this with a close, which is where the connection is torn down.
 unsafe fn on_error_<WebSocketId, EventType>(data: &mut WebSocket)
 where
     EventType: Send + From<WebSocketEvent<WebSocketId>> + 'static,
     WebSocketId: Send + Clone + 'static,
 {
+    let id = (*(data.id as *mut WebSocketId)).clone();
+    let post_box = &*(data.post_box as *const PostBox<EventType>);
+    post_box.post(WebSocketEvent::error(
+        id,
+        data.browser_error(FailurePhase::Open, None),
+    ));
 }
 
-impl<EventType> WebSocketContext<EventType> {
-    pub fn start_connect<WebSocketId>(&mut self, id: WebSocketId, request: &str) -> Result<()>
-    where
-        EventType: Send + From<WebSocketEvent<WebSocketId>> + 'static,
-        WebSocketId: Send + Clone + 'static,
-    {
-        let id_box = Box::new(id);
-        let post_box_box = Box::new(self.post_box.clone());
-        let connecting_cbs = Box::new(ConnectingCbs {
-            data: WebSocket {
-                id: Box::into_raw(id_box) as _,
-                post_box: Box::into_raw(post_box_box) as _,
-            },
-            on_open: on_open_::<WebSocketId, EventType>,
-            on_connection_failed: connection_failed_::<WebSocketId, EventType>,
-        });
-        let url_str =
-            CString::new(request).map_err(|_| Error::Url(Cow::Owned(request.to_string())))?;
-        unsafe {
-            websocket_start_connect(
-                Box::into_raw(connecting_cbs) as _,
-                url_str.as_ptr(),
-                url_str.as_bytes().len() as u32,
-            );
-        }
-        Ok(())
+#[no_mangle]
+pub unsafe extern "C" fn on_timeout(data: *mut c_void) {
+    // The JS side has detached from the socket, so this is the last callback for it
+    let RunningCbs {
+        data, on_timeout, ..
+    } = *Box::from_raw(data as *mut RunningCbs);
+    on_timeout(data);
+}
+
+unsafe fn on_timeout_<WebSocketId, EventType>(mut data: WebSocket)
+where
+    EventType: Send + From<WebSocketEvent<WebSocketId>> + 'static,
+    WebSocketId: Send + Clone + 'static,
+{
+    let id = (*(data.id as *mut WebSocketId)).clone();
+    let post_box = &*(data.post_box as *const PostBox<EventType>);
+    post_box.post(WebSocketEvent::timeout(id.clone()));
+    if let Some(delay) = reconnect_delay(&mut data) {
+        data.registration.set_sink(None);
+        post_box.post(WebSocketEvent::reconnecting(id, data.attempt, delay));
+        start_attempt(connecting_cbs::<WebSocketId, EventType>(data), delay);
+    } else {
+        free::<WebSocketId, EventType>(data);
     }
 }
+
+/// Start connecting, for `WebSocketContext::start_connect`. Returns what cancels connecting or
+/// waiting to reconnect.
+pub(crate) fn connect<WebSocketId, EventType>(
+    id: WebSocketId,
+    request: ConnectRequest,
+    post_box: PostBox<EventType>,
+    registration: Registration,
+) -> Rc<dyn Fn()>
+where
+    EventType: Send + From<WebSocketEvent<WebSocketId>> + 'static,
+    WebSocketId: Send + Clone + 'static,
+{
+    let protocols = request.protocols.join(",");
+    let heartbeat_message = match &request.heartbeat {
+        Some(heartbeat) => heartbeat.fallback_message.clone(),
+        None => String::new(),
+    };
+    let idle_timeout = request.idle_timeout();
+    let key = registration.key;
+    let id_box = Box::new(id);
+    let post_box_box = Box::new(post_box);
+    let data = WebSocket {
+        id: Box::into_raw(id_box) as _,
+        post_box: Box::into_raw(post_box_box) as _,
+        url: request.url,
+        protocols,
+        heartbeat: request.heartbeat,
+        heartbeat_message,
+        connect_timeout: request.connect_timeout,
+        idle_timeout,
+        reconnect: request.reconnect,
+        attempt: 0,
+        connected: false,
+        registration,
+    };
+    unsafe {
+        start_attempt(
+            connecting_cbs::<WebSocketId, EventType>(data),
+            Duration::from_secs(0),
+        );
+    }
+    // The JS side calls back in to report the cancel
+    Rc::new(move || unsafe { websocket_cancel_connect(key) })
+}
+
 impl WebSocketSink {
+    /// Whether the connection is still open, from the browser's `readyState`. Sending fails
+    /// with `Error::AlreadyClosed` once it's `Closing` or `Closed`.
+    pub fn state(&self) -> ConnectionState {
+        match unsafe { websocket_ready_state(self.inner_id) } {
+            0 => ConnectionState::Connecting,
+            1 => ConnectionState::Open,
+            2 => ConnectionState::Closing,
+            _ => ConnectionState::Closed,
+        }
+    }
+
+    fn check_open(&self) -> Result<()> {
+        match self.state() {
+            ConnectionState::Open => Ok(()),
+            _ => Err(Error::AlreadyClosed),
+        }
+    }
+
     pub fn send(&mut self, msg: String) -> Result<()> {
-        // TODO Proper error
-        let msg_str = CString::new(msg).map_err(|_| Error::UnsupportedDataFrame)?;
+        self.check_open()?;
+        // The JS side copies the text out before this returns
+        unsafe {
+            websocket_send(self.inner_id, msg.as_ptr(), msg.len() as u32);
+        }
+        Ok(())
+    }
+
+    pub fn send_binary(&mut self, data: Vec<u8>) -> Result<()> {
+        self.check_open()?;
+        unsafe {
+            websocket_send_binary(self.inner_id, data.as_ptr(), data.len() as u32);
+        }
+        Ok(())
+    }
+
+    /// The browser queues messages itself and never blocks, so this is the same as `send`.
+    pub fn try_send(&mut self, msg: String) -> Result<()> {
+        self.send(msg)
+    }
+
+    pub fn try_send_binary(&mut self, data: Vec<u8>) -> Result<()> {
+        self.send_binary(data)
+    }
+
+    pub(crate) async fn send_async(&mut self, msg: OutgoingMessage) -> Result<()> {
+        self.send_message(msg)
+    }
+
+    pub(crate) fn send_message(&mut self, msg: OutgoingMessage) -> Result<()> {
+        match msg {
+            OutgoingMessage::Text(msg) => self.send(msg),
+            OutgoingMessage::Binary(data) => self.send_binary(data),
+        }
+    }
+
+    /// The browser's queue is never full, so the policy has no effect on wasm.
+    pub fn set_overflow_policy(&mut self, _policy: OverflowPolicy) {}
+
+    /// Start the closing handshake. The connection isn't closed until the `CloseMessage` and
+    /// `ConnectionClosed` events are received. Nothing more can be sent after this.
+    ///
+    /// Browsers only allow the code 1000 or codes in the range 3000-4999.
+    pub fn close(&mut self, code: CloseCode, reason: String) -> Result<()> {
+        self.check_open()?;
+        check_browser_close_code(code)?;
+        check_close_reason(&reason)?;
         unsafe {
-            websocket_send(
+            websocket_close(
                 self.inner_id,
-                msg_str.as_ptr(),
-                msg_str.as_bytes().len() as u32,
+                u16::from(code) as u32,
+                reason.as_ptr(),
+                reason.len() as u32,
             );
         }
         Ok(())
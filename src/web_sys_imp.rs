@@ -0,0 +1,480 @@
+//! The wasm backend built on wasm-bindgen and `web_sys::WebSocket`, selected with the `bindgen`
+//! feature. It doesn't need miniquad's JS loader or `js/websockets.js`, so it works in projects
+//! built with wasm-pack or trunk.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::mem;
+use std::rc::Rc;
+use std::time::Duration;
+
+use js_sys::{Array, ArrayBuffer, Function, Reflect, Uint8Array};
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::convert::FromWasmAbi;
+use wasm_bindgen::prelude::wasm_bindgen;
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{BinaryType, CloseEvent, MessageEvent};
+
+use crate::error::{check_browser_close_code, check_close_reason, Error, FailurePhase, Result};
+use crate::event_sink::PostBox;
+pub(crate) use crate::wasm_context::spawn_connect;
+use crate::wasm_context::Registration;
+pub use crate::wasm_context::{init, init_polling, ConnectHandle, WebSocketContext};
+use crate::{
+    CloseCode, ConnectRequest, ConnectionState, OutgoingMessage, OverflowPolicy, WebSocketEvent,
+};
+
+#[wasm_bindgen]
+extern "C" {
+    // Bound from the global scope rather than `Window` so that this also works in workers
+    #[wasm_bindgen(js_name = setTimeout)]
+    fn set_timeout(handler: &Function, timeout: i32) -> JsValue;
+    #[wasm_bindgen(js_name = clearTimeout)]
+    fn clear_timeout(handle: &JsValue);
+    #[wasm_bindgen(js_name = setInterval)]
+    fn set_interval(handler: &Function, timeout: i32) -> JsValue;
+    #[wasm_bindgen(js_name = clearInterval)]
+    fn clear_interval(handle: &JsValue);
+}
+
+/// Sends messages on a connection.
+#[derive(Clone)]
+pub struct WebSocketSink {
+    ws: web_sys::WebSocket,
+}
+
+/// Set on the browser socket when it's closed from this side, so that it isn't reconnected.
+const CLOSED_LOCALLY: &str = "closed_locally";
+
+fn timeout_ms(timeout: Duration) -> i32 {
+    timeout.as_millis().min(i32::MAX as u128).max(1) as i32
+}
+
+/// Drives one connection through its attempts and reconnections. The browser callbacks each
+/// hold a reference to it, so it lives until the connection has ended for good.
+struct Runner<WebSocketId, EventType> {
+    id: WebSocketId,
+    post_box: PostBox<EventType>,
+    request: ConnectRequest,
+    registration: Registration,
+    state: RefCell<RunnerState>,
+}
+
+#[derive(Default)]
+struct RunnerState {
+    attempt: u32,
+    connected: bool,
+    done: bool,
+    /// Bumped for each socket and each wait to reconnect, so that callbacks left over from an
+    /// earlier one are ignored.
+    generation: u32,
+    ws: Option<web_sys::WebSocket>,
+    timeouts: Vec<JsValue>,
+    heartbeat: Option<JsValue>,
+    /// The idle timeout, which is restarted whenever something is received.
+    idle: Option<(Function, JsValue)>,
+    /// The closures for the current generation. The browser only holds references to them so
+    /// they have to be kept alive here.
+    closures: Vec<Box<dyn Any>>,
+}
+
+/// Drop closures once the callback that's running has returned, as it may be one of them.
+fn drop_later(closures: Vec<Box<dyn Any>>) {
+    if closures.is_empty() {
+        return;
+    }
+    let drop_closures = Closure::once_into_js(move || drop(closures));
+    set_timeout(drop_closures.unchecked_ref(), 0);
+}
+
+impl<WebSocketId, EventType> Runner<WebSocketId, EventType>
+where
+    EventType: From<WebSocketEvent<WebSocketId>> + 'static,
+    WebSocketId: Clone + 'static,
+{
+    /// A closure that calls `f` unless the connection has moved on from `generation`.
+    fn callback<T, F>(self: &Rc<Self>, generation: u32, f: F) -> Closure<dyn FnMut(T)>
+    where
+        T: FromWasmAbi + 'static,
+        F: Fn(&Rc<Self>, T) + 'static,
+    {
+        let runner = self.clone();
+        Closure::wrap(Box::new(move |arg: T| {
+            let current = {
+                let state = runner.state.borrow();
+                !state.done && state.generation == generation
+            };
+            if current {
+                f(&runner, arg);
+            }
+        }) as Box<dyn FnMut(T)>)
+    }
+
+    /// Detach from the current socket, closing it if it's still open, and stop its timers.
+    fn next_generation(&self) -> u32 {
+        let mut state = self.state.borrow_mut();
+        state.generation += 1;
+        for timeout in state.timeouts.drain(..) {
+            clear_timeout(&timeout);
+        }
+        if let Some(heartbeat) = state.heartbeat.take() {
+            clear_interval(&heartbeat);
+        }
+        if let Some((_, idle)) = state.idle.take() {
+            clear_timeout(&idle);
+        }
+        if let Some(ws) = state.ws.take() {
+            ws.set_onopen(None);
+            ws.set_onmessage(None);
+            ws.set_onclose(None);
+            ws.set_onerror(None);
+            let _ = ws.close();
+        }
+        drop_later(mem::take(&mut state.closures));
+        state.generation
+    }
+
+    fn keep<T: ?Sized + 'static>(&self, closure: Closure<T>) {
+        self.state.borrow_mut().closures.push(Box::new(closure));
+    }
+
+    fn after<F: Fn(&Rc<Self>) + 'static>(
+        self: &Rc<Self>,
+        generation: u32,
+        timeout: Duration,
+        f: F,
+    ) {
+        let closure = self.callback(generation, move |runner, _: JsValue| f(runner));
+        let handle = set_timeout(closure.as_ref().unchecked_ref(), timeout_ms(timeout));
+        self.state.borrow_mut().timeouts.push(handle);
+        self.keep(closure);
+    }
+
+    fn browser_error(&self, phase: FailurePhase, code: Option<u16>) -> Error {
+        Error::Browser {
+            phase,
+            url: self.request.url.clone(),
+            code,
+        }
+    }
+
+    fn connect(self: &Rc<Self>) {
+        let generation = self.next_generation();
+        let ws = if self.request.protocols.is_empty() {
+            web_sys::WebSocket::new(&self.request.url)
+        } else {
+            let protocols: Array = self
+                .request
+                .protocols
+                .iter()
+                .map(|protocol| JsValue::from_str(protocol))
+                .collect();
+            web_sys::WebSocket::new_with_str_sequence(&self.request.url, &protocols)
+        };
+        let ws = match ws {
+            Ok(ws) => ws,
+            // The browser throws straight away for a bad URL or subprotocol
+            Err(_) => {
+                self.attempt_failed(self.browser_error(FailurePhase::Connecting, None));
+                return;
+            }
+        };
+        ws.set_binary_type(BinaryType::Arraybuffer);
+
+        let on_open = self.callback(generation, |runner, _: JsValue| runner.opened());
+        ws.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+        self.keep(on_open);
+        // Browsers don't say why a connection failed, only the close code that follows the error
+        let on_close = self.callback(generation, |runner, event: CloseEvent| {
+            let err = runner.browser_error(FailurePhase::Connecting, Some(event.code()));
+            runner.attempt_failed(err);
+        });
+        ws.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+        self.keep(on_close);
+        self.state.borrow_mut().ws = Some(ws);
+
+        if let Some(timeout) = self.request.connect_timeout {
+            self.after(generation, timeout, |runner| {
+                runner.attempt_failed(Error::Timeout)
+            });
+        }
+    }
+
+    fn opened(self: &Rc<Self>) {
+        let generation;
+        let ws;
+        let connected;
+        {
+            let mut state = self.state.borrow_mut();
+            generation = state.generation;
+            ws = state.ws.clone().unwrap();
+            connected = state.connected;
+            state.connected = true;
+            state.attempt = 0;
+            // Only the connect timeout has been started so far
+            for timeout in state.timeouts.drain(..) {
+                clear_timeout(&timeout);
+            }
+        }
+
+        let on_message = self.callback(generation, |runner, event: MessageEvent| {
+            runner.received(event.data())
+        });
+        ws.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+        self.keep(on_message);
+        // The browser follows this with a close, which is where the connection is torn down
+        let on_error = self.callback(generation, |runner, _: JsValue| {
+            let err = runner.browser_error(FailurePhase::Open, None);
+            runner
+                .post_box
+                .post(WebSocketEvent::error(runner.id.clone(), err));
+        });
+        ws.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+        self.keep(on_error);
+        let on_close = self.callback(generation, |runner, event: CloseEvent| runner.closed(event));
+        ws.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+        self.keep(on_close);
+
+        if let Some(heartbeat) = &self.request.heartbeat {
+            let message = heartbeat.fallback_message.clone();
+            let send = self.callback(generation, move |runner, _: JsValue| {
+                if let Some(ws) = &runner.state.borrow().ws {
+                    let _ = ws.send_with_str(&message);
+                }
+            });
+            let handle = set_interval(
+                send.as_ref().unchecked_ref(),
+                timeout_ms(heartbeat.interval),
+            );
+            self.state.borrow_mut().heartbeat = Some(handle);
+            self.keep(send);
+        }
+        if let Some(timeout) = self.request.idle_timeout() {
+            let timed_out = self.callback(generation, |runner, _: JsValue| runner.timed_out());
+            let function: Function = timed_out.as_ref().unchecked_ref::<Function>().clone();
+            let handle = set_timeout(&function, timeout_ms(timeout));
+            self.state.borrow_mut().idle = Some((function, handle));
+            self.keep(timed_out);
+        }
+
+        let sink = WebSocketSink { ws: ws.clone() };
+        self.registration.set_sink(Some(sink.clone()));
+        let id = self.id.clone();
+        if connected {
+            self.post_box.post(WebSocketEvent::reconnected(id, sink));
+        } else {
+            // The browser reports an empty string when no subprotocol was negotiated
+            let protocol = Some(ws.protocol()).filter(|protocol| !protocol.is_empty());
+            self.post_box
+                .post(WebSocketEvent::connected(id, sink, protocol));
+        }
+    }
+
+    fn received(&self, data: JsValue) {
+        if let Some(timeout) = self.request.idle_timeout() {
+            let mut state = self.state.borrow_mut();
+            if let Some((function, handle)) = &mut state.idle {
+                clear_timeout(handle);
+                *handle = set_timeout(function, timeout_ms(timeout));
+            }
+        }
+        let id = self.id.clone();
+        if let Some(msg) = data.as_string() {
+            self.post_box.post(WebSocketEvent::message(id, msg));
+        } else if let Some(buffer) = data.dyn_ref::<ArrayBuffer>() {
+            let bytes = Uint8Array::new(buffer).to_vec();
+            self.post_box.post(WebSocketEvent::binary(id, bytes));
+        }
+    }
+
+    fn closed(self: &Rc<Self>, event: CloseEvent) {
+        let closed_locally = {
+            let state = self.state.borrow();
+            let ws = state.ws.as_ref().unwrap();
+            let closed_locally = Reflect::get(ws, &JsValue::from_str(CLOSED_LOCALLY));
+            matches!(closed_locally, Ok(closed_locally) if closed_locally.is_truthy())
+        };
+        self.registration.set_sink(None);
+        let id = self.id.clone();
+        self.post_box.post(WebSocketEvent::close_msg(
+            id.clone(),
+            event.code().into(),
+            event.reason(),
+            event.was_clean(),
+        ));
+        self.post_box.post(WebSocketEvent::connection_closed(id));
+        if !closed_locally {
+            if let Some(delay) = self.reconnect_delay() {
+                self.reconnect(delay);
+                return;
+            }
+        }
+        self.finish();
+    }
+
+    fn timed_out(self: &Rc<Self>) {
+        self.registration.set_sink(None);
+        self.post_box.post(WebSocketEvent::timeout(self.id.clone()));
+        match self.reconnect_delay() {
+            Some(delay) => self.reconnect(delay),
+            None => self.finish(),
+        }
+    }
+
+    fn attempt_failed(self: &Rc<Self>, err: Error) {
+        match self.reconnect_delay() {
+            Some(delay) => self.reconnect(delay),
+            None => {
+                self.post_box
+                    .post(WebSocketEvent::connection_failed(self.id.clone(), err));
+                self.finish();
+            }
+        }
+    }
+
+    /// Move on to the next attempt, returns the delay before it or `None` if we should give up.
+    fn reconnect_delay(&self) -> Option<Duration> {
+        let mut state = self.state.borrow_mut();
+        state.attempt += 1;
+        let attempt = state.attempt;
+        self.request
+            .reconnect
+            .as_ref()
+            .and_then(|policy| policy.delay(attempt, js_sys::Math::random()))
+    }
+
+    fn reconnect(self: &Rc<Self>, delay: Duration) {
+        let generation = self.next_generation();
+        let attempt = self.state.borrow().attempt;
+        self.post_box.post(WebSocketEvent::reconnecting(
+            self.id.clone(),
+            attempt,
+            delay,
+        ));
+        self.after(generation, delay, |runner| runner.connect());
+    }
+
+    /// Stop connecting or waiting to reconnect, see `ConnectHandle::cancel`.
+    fn cancel(&self) {
+        if self.state.borrow().done {
+            return;
+        }
+        self.post_box
+            .post(WebSocketEvent::cancelled(self.id.clone()));
+        self.finish();
+    }
+
+    /// Called once the connection has ended for good.
+    fn finish(&self) {
+        self.next_generation();
+        self.state.borrow_mut().done = true;
+        // The closures holding the other references are dropped by `next_generation`
+        self.registration.unregister();
+    }
+}
+
+/// Start connecting, for `WebSocketContext::start_connect`. Returns what cancels connecting or
+/// waiting to reconnect.
+pub(crate) fn connect<WebSocketId, EventType>(
+    id: WebSocketId,
+    request: ConnectRequest,
+    post_box: PostBox<EventType>,
+    registration: Registration,
+) -> Rc<dyn Fn()>
+where
+    EventType: Send + From<WebSocketEvent<WebSocketId>> + 'static,
+    WebSocketId: Send + Clone + 'static,
+{
+    let runner = Rc::new(Runner {
+        id,
+        post_box,
+        request,
+        registration,
+        state: Default::default(),
+    });
+    runner.connect();
+    Rc::new(move || runner.cancel())
+}
+
+impl WebSocketSink {
+    /// Whether the connection is still open, from the browser's `readyState`. Sending fails
+    /// with `Error::AlreadyClosed` once it's `Closing` or `Closed`.
+    pub fn state(&self) -> ConnectionState {
+        match self.ws.ready_state() {
+            web_sys::WebSocket::CONNECTING => ConnectionState::Connecting,
+            web_sys::WebSocket::OPEN => ConnectionState::Open,
+            web_sys::WebSocket::CLOSING => ConnectionState::Closing,
+            _ => ConnectionState::Closed,
+        }
+    }
+
+    fn check_open(&self) -> Result<()> {
+        match self.state() {
+            ConnectionState::Open => Ok(()),
+            _ => Err(Error::AlreadyClosed),
+        }
+    }
+
+    /// The browser only throws here if the socket isn't open, which `check_open` has ruled out,
+    /// but it's reported rather than ignored.
+    fn send_error(&self) -> Error {
+        Error::Browser {
+            phase: FailurePhase::Open,
+            url: self.ws.url(),
+            code: None,
+        }
+    }
+
+    pub fn send(&mut self, msg: String) -> Result<()> {
+        self.check_open()?;
+        self.ws.send_with_str(&msg).map_err(|_| self.send_error())
+    }
+
+    pub fn send_binary(&mut self, data: Vec<u8>) -> Result<()> {
+        self.check_open()?;
+        self.ws
+            .send_with_u8_array(&data)
+            .map_err(|_| self.send_error())
+    }
+
+    /// The browser queues messages itself and never blocks, so this is the same as `send`.
+    pub fn try_send(&mut self, msg: String) -> Result<()> {
+        self.send(msg)
+    }
+
+    pub fn try_send_binary(&mut self, data: Vec<u8>) -> Result<()> {
+        self.send_binary(data)
+    }
+
+    pub(crate) async fn send_async(&mut self, msg: OutgoingMessage) -> Result<()> {
+        self.send_message(msg)
+    }
+
+    pub(crate) fn send_message(&mut self, msg: OutgoingMessage) -> Result<()> {
+        match msg {
+            OutgoingMessage::Text(msg) => self.send(msg),
+            OutgoingMessage::Binary(data) => self.send_binary(data),
+        }
+    }
+
+    /// The browser's queue is never full, so the policy has no effect on wasm.
+    pub fn set_overflow_policy(&mut self, _policy: OverflowPolicy) {}
+
+    /// Start the closing handshake. The connection isn't closed until the `CloseMessage` and
+    /// `ConnectionClosed` events are received. Nothing more can be sent after this.
+    ///
+    /// Browsers only allow the code 1000 or codes in the range 3000-4999.
+    pub fn close(&mut self, code: CloseCode, reason: String) -> Result<()> {
+        self.check_open()?;
+        check_browser_close_code(code)?;
+        check_close_reason(&reason)?;
+        let _ = Reflect::set(
+            &self.ws,
+            &JsValue::from_str(CLOSED_LOCALLY),
+            &JsValue::from_bool(true),
+        );
+        self.ws
+            .close_with_code_and_reason(u16::from(code), &reason)
+            .map_err(|_| self.send_error())
+    }
+}
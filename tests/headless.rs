@@ -0,0 +1,596 @@
+#![cfg(not(target_arch = "wasm32"))]
+
+use futures_util::future::pending;
+use futures_util::SinkExt;
+use miniquad_websockets::{
+    connect, init_headless, init_polling, init_with_handle, CloseCode, ConnectRequest,
+    ConnectionState, Error, Heartbeat, ReconnectPolicy, WebSocketContext, WebSocketEvent,
+    WebSocketEventKind,
+};
+#[cfg(any(feature = "tls-native", feature = "tls-rustls"))]
+use miniquad_websockets::{EventReceiver, TlsConfig};
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Builder;
+use tokio::stream::StreamExt;
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Wait for the next event and match its kind against `pattern`, evaluating to `value`.
+macro_rules! expect_event {
+    ($events:expr, $pattern:pat => $value:expr) => {
+        match $events.recv_timeout(TIMEOUT).unwrap().kind {
+            $pattern => $value,
+            kind => panic!("Expected {} got {:?}", stringify!($pattern), kind),
+        }
+    };
+    ($events:expr, $pattern:pat) => {
+        expect_event!($events, $pattern => ())
+    };
+}
+
+/// Start a server that runs `handle` on each connection, returns the port.
+fn serve<F, Fut>(handle: F) -> u16
+where
+    F: Fn(TcpStream) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    serve_on("127.0.0.1:0", handle)
+}
+
+/// Like `serve` but listening on `addr`.
+fn serve_on<F, Fut>(addr: &str, handle: F) -> u16
+where
+    F: Fn(TcpStream) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let mut runtime = Builder::new()
+        .basic_scheduler()
+        .enable_io()
+        .build()
+        .unwrap();
+    let mut listener = runtime.block_on(TcpListener::bind(addr)).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        runtime.block_on(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(handle(socket));
+            }
+        })
+    });
+    port
+}
+
+/// Echo everything back on an accepted connection.
+async fn echo_connection<S: AsyncRead + AsyncWrite + Unpin>(stream: S) {
+    let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+    while let Some(Ok(msg)) = ws.next().await {
+        if !msg.is_close() {
+            ws.send(msg).await.unwrap();
+        }
+    }
+}
+
+/// Wait until `drain` returns something, for a context made with `init_polling`.
+fn drain_some(context: &mut WebSocketContext<WebSocketEvent<u32>>) -> Vec<WebSocketEvent<u32>> {
+    let started = Instant::now();
+    loop {
+        let events = context.drain();
+        if !events.is_empty() {
+            return events;
+        }
+        assert!(started.elapsed() < TIMEOUT, "No events");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Start a server that echoes everything back, returns the port.
+fn echo_server() -> u16 {
+    serve(echo_connection)
+}
+
+/// Start an echo server for `wss://localhost`, with a certificate issued by the test CA in
+/// tests/tls, returns the port.
+#[cfg(any(feature = "tls-native", feature = "tls-rustls"))]
+fn tls_echo_server() -> u16 {
+    use std::sync::Arc;
+    use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys};
+    use tokio_rustls::rustls::{NoClientAuth, ServerConfig};
+    use tokio_rustls::TlsAcceptor;
+
+    let chain = certs(&mut &include_bytes!("tls/localhost.pem")[..]).unwrap();
+    let key = pkcs8_private_keys(&mut &include_bytes!("tls/localhost.key")[..])
+        .unwrap()
+        .remove(0);
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(chain, key).unwrap();
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+    serve(move |socket| {
+        let accepting = acceptor.accept(socket);
+        async move {
+            // The client hangs up when it doesn't trust the certificate
+            if let Ok(stream) = accepting.await {
+                echo_connection(stream).await;
+            }
+        }
+    })
+}
+
+/// Start connecting to `tls_echo_server`.
+#[cfg(any(feature = "tls-native", feature = "tls-rustls"))]
+fn tls_connect(tls: TlsConfig) -> (WebSocketContext<WebSocketEvent<()>>, EventReceiver<()>) {
+    let port = tls_echo_server();
+    let (mut context, events) = init_headless().unwrap();
+    let request = ConnectRequest::new(format!("wss://localhost:{}/", port)).tls(tls);
+    context.start_connect((), request).unwrap();
+    (context, events)
+}
+
+/// Start a server that opens the connection and then never reads or sends anything, so pings
+/// aren't answered.
+fn silent_server() -> u16 {
+    serve(|socket| async move {
+        let _ws = tokio_tungstenite::accept_async(socket).await.unwrap();
+        pending::<()>().await;
+    })
+}
+
+/// Start a server that accepts connections but never answers the handshake.
+fn stalled_server() -> u16 {
+    serve(|socket| async move {
+        let _socket = socket;
+        pending::<()>().await;
+    })
+}
+
+#[test]
+fn echo() {
+    let port = echo_server();
+    let (mut context, events) = init_headless().unwrap();
+    context
+        .start_connect(1, format!("ws://127.0.0.1:{}/", port))
+        .unwrap();
+
+    let event = events.recv_timeout(TIMEOUT).unwrap();
+    assert_eq!(event.id, 1);
+    let mut sink = match event.kind {
+        WebSocketEventKind::Connected(sink, None) => sink,
+        kind => panic!("Expected Connected got {:?}", kind),
+    };
+    assert_eq!(sink.state(), ConnectionState::Open);
+
+    sink.send(String::from("hello")).unwrap();
+    expect_event!(events, WebSocketEventKind::Message(msg) => assert_eq!(msg, "hello"));
+    sink.send_binary(vec![1, 2, 3]).unwrap();
+    expect_event!(events, WebSocketEventKind::Binary(data) => assert_eq!(data, vec![1, 2, 3]));
+
+    assert!(matches!(
+        sink.close(CloseCode::Abnormal, String::new()),
+        Err(Error::Protocol(_))
+    ));
+    sink.close(CloseCode::Normal, String::from("bye")).unwrap();
+    assert_eq!(sink.state(), ConnectionState::Closing);
+    assert!(matches!(
+        sink.send(String::from("late")),
+        Err(Error::AlreadyClosed)
+    ));
+    expect_event!(events, WebSocketEventKind::CloseMessage(Some(frame)) => {
+        assert_eq!(frame.code, CloseCode::Normal)
+    });
+    expect_event!(events, WebSocketEventKind::ConnectionClosed);
+    assert_eq!(sink.state(), ConnectionState::Closed);
+    assert!(matches!(
+        sink.close(CloseCode::Normal, String::new()),
+        Err(Error::AlreadyClosed)
+    ));
+}
+
+#[test]
+fn ipv6_literal() {
+    let port = serve_on("[::1]:0", echo_connection);
+    let (mut context, events) = init_headless().unwrap();
+    context
+        .start_connect((), format!("ws://[::1]:{}/", port))
+        .unwrap();
+    let mut sink = expect_event!(events, WebSocketEventKind::Connected(sink, None) => sink);
+    sink.send(String::from("hello")).unwrap();
+    expect_event!(events, WebSocketEventKind::Message(msg) => assert_eq!(msg, "hello"));
+}
+
+#[test]
+fn request_headers() {
+    let (tx, requests) = mpsc::channel();
+    let port = serve(move |socket| {
+        let tx = tx.clone();
+        async move {
+            // tungstenite picks the error type
+            #[allow(clippy::result_large_err)]
+            let callback = |request: &Request, mut response: Response| {
+                let header = |name| {
+                    request
+                        .headers()
+                        .get(name)
+                        .map(|value| value.to_str().unwrap().to_owned())
+                };
+                tx.send((
+                    header("X-Player"),
+                    header("Origin"),
+                    header("Sec-WebSocket-Protocol"),
+                ))
+                .unwrap();
+                response.headers_mut().insert(
+                    "Sec-WebSocket-Protocol",
+                    HeaderValue::from_static("game.v2"),
+                );
+                Ok(response)
+            };
+            let _ws = tokio_tungstenite::accept_hdr_async(socket, callback)
+                .await
+                .unwrap();
+            pending::<()>().await;
+        }
+    });
+
+    let (mut context, events) = init_headless().unwrap();
+    let request = ConnectRequest::new(format!("ws://127.0.0.1:{}/", port))
+        .header("X-Player", "1")
+        .origin("http://example.com")
+        .protocol("game.v1")
+        .protocol("game.v2");
+    context.start_connect((), request).unwrap();
+    let protocol =
+        expect_event!(events, WebSocketEventKind::Connected(_, Some(protocol)) => protocol);
+    assert_eq!(protocol, "game.v2");
+    assert_eq!(
+        requests.recv_timeout(TIMEOUT).unwrap(),
+        (
+            Some(String::from("1")),
+            Some(String::from("http://example.com")),
+            Some(String::from("game.v1, game.v2"))
+        )
+    );
+}
+
+#[test]
+fn registry() {
+    let port = echo_server();
+    let (mut context, events) = init_headless::<u32>().unwrap();
+    let url = format!("ws://127.0.0.1:{}/", port);
+    context.start_connect(1, url.as_str()).unwrap();
+    context.start_connect(2, url.as_str()).unwrap();
+    // The sinks are dropped, the context keeps the connections open
+    for _ in 0..2 {
+        expect_event!(events, WebSocketEventKind::Connected(_, None));
+    }
+    assert!(context.is_open(&1u32));
+    assert!(!context.is_open(&3u32));
+    assert_eq!(
+        context.connections::<u32>().collect::<Vec<_>>(),
+        vec![(1, ConnectionState::Open), (2, ConnectionState::Open)]
+    );
+
+    context.send_to(&2u32, "hello").unwrap();
+    let event = events.recv_timeout(TIMEOUT).unwrap();
+    assert_eq!(event.id, 2);
+    match event.kind {
+        WebSocketEventKind::Message(msg) => assert_eq!(msg, "hello"),
+        kind => panic!("Expected Message got {:?}", kind),
+    }
+    assert!(matches!(
+        context.send_to(&3u32, "hello"),
+        Err(Error::NotConnected)
+    ));
+
+    assert_eq!(context.broadcast(vec![1, 2, 3]), 2);
+    let mut ids: Vec<_> = (0..2)
+        .map(|_| events.recv_timeout(TIMEOUT).unwrap().id)
+        .collect();
+    ids.sort_unstable();
+    assert_eq!(ids, vec![1, 2]);
+
+    context
+        .close(&1u32, CloseCode::Normal, String::new())
+        .unwrap();
+    assert!(!context.is_open(&1u32));
+}
+
+#[test]
+fn registry_uses_first_started() {
+    let (mut context, events) = init_headless().unwrap();
+    // Only the first one started will echo the message back
+    context
+        .start_connect(1, format!("ws://127.0.0.1:{}/", echo_server()))
+        .unwrap();
+    context
+        .start_connect(1, format!("ws://127.0.0.1:{}/", silent_server()))
+        .unwrap();
+    for _ in 0..2 {
+        expect_event!(events, WebSocketEventKind::Connected(_, None));
+    }
+
+    context.send_to(&1, "hello").unwrap();
+    expect_event!(events, WebSocketEventKind::Message(msg) => assert_eq!(msg, "hello"));
+}
+
+#[test]
+fn shutdown_closes_connections() {
+    let port = echo_server();
+    let (mut context, events) = init_headless().unwrap();
+    context
+        .start_connect(1, format!("ws://127.0.0.1:{}/", port))
+        .unwrap();
+    let _sink = expect_event!(events, WebSocketEventKind::Connected(sink, None) => sink);
+
+    assert!(context.shutdown(TIMEOUT));
+    // The server replies with Normal whatever code it's sent
+    expect_event!(events, WebSocketEventKind::CloseMessage(Some(_)));
+    expect_event!(events, WebSocketEventKind::ConnectionClosed);
+}
+
+#[test]
+fn polling() {
+    let port = echo_server();
+    let mut context = init_polling().unwrap();
+    context
+        .start_connect(1u32, format!("ws://127.0.0.1:{}/", port))
+        .unwrap();
+    let mut events = drain_some(&mut context);
+    assert_eq!(events.len(), 1);
+    let event = events.remove(0);
+    assert_eq!(event.id, 1);
+    let mut sink = match event.kind {
+        WebSocketEventKind::Connected(sink, None) => sink,
+        kind => panic!("Expected Connected got {:?}", kind),
+    };
+
+    sink.send(String::from("hello")).unwrap();
+    let events = drain_some(&mut context);
+    assert!(matches!(
+        events.as_slice(),
+        [WebSocketEvent { id: 1, kind: WebSocketEventKind::Message(msg) }] if msg == "hello"
+    ));
+    assert!(context.drain().is_empty());
+}
+
+#[test]
+fn connection_refused() {
+    let (mut context, events) = init_headless().unwrap();
+    // Bind then drop a listener to get a port that nothing is listening on
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    context
+        .start_connect((), format!("ws://127.0.0.1:{}/", port))
+        .unwrap();
+    expect_event!(events, WebSocketEventKind::ConnectionFailed(_));
+}
+
+#[test]
+fn with_handle() {
+    let port = echo_server();
+    let mut runtime = Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .unwrap();
+    let handle = runtime.handle().clone();
+    thread::spawn(move || runtime.block_on(pending::<()>()));
+
+    let (tx, events) = mpsc::channel::<WebSocketEvent<u32>>();
+    let mut context = init_with_handle(tx, handle).unwrap();
+    context
+        .start_connect(1, format!("ws://127.0.0.1:{}/", port))
+        .unwrap();
+    let mut sink = expect_event!(events, WebSocketEventKind::Connected(sink, None) => sink);
+    sink.send(String::from("hello")).unwrap();
+    expect_event!(events, WebSocketEventKind::Message(msg) => assert_eq!(msg, "hello"));
+}
+
+#[test]
+fn async_echo() {
+    let port = echo_server();
+    let mut runtime = Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async move {
+        let (mut sink, mut events) = connect(format!("ws://127.0.0.1:{}/", port)).await.unwrap();
+        match events.next().await.unwrap().kind {
+            WebSocketEventKind::Connected(_, None) => {}
+            kind => panic!("Expected Connected got {:?}", kind),
+        }
+
+        sink.send(String::from("hello")).await.unwrap();
+        match tokio::time::timeout(TIMEOUT, events.next()).await.unwrap() {
+            Some(event) => match event.kind {
+                WebSocketEventKind::Message(msg) => assert_eq!(msg, "hello"),
+                kind => panic!("Expected Message got {:?}", kind),
+            },
+            None => panic!("Stream ended"),
+        }
+
+        sink.close(CloseCode::Normal, String::from("bye")).unwrap();
+        let mut kinds = Vec::new();
+        while let Some(event) = tokio::time::timeout(TIMEOUT, events.next()).await.unwrap() {
+            kinds.push(event.kind);
+        }
+        assert!(matches!(
+            kinds.as_slice(),
+            [
+                WebSocketEventKind::CloseMessage(Some(_)),
+                WebSocketEventKind::ConnectionClosed
+            ]
+        ));
+    });
+}
+
+#[test]
+fn cancel_reconnecting() {
+    let (mut context, events) = init_headless().unwrap();
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let request =
+        ConnectRequest::new(format!("ws://127.0.0.1:{}/", port)).reconnect(ReconnectPolicy {
+            initial_delay: Duration::from_secs(60),
+            multiplier: 1.0,
+            max_delay: Duration::from_secs(60),
+            jitter: 0.0,
+            max_attempts: None,
+        });
+    let handle = context.start_connect(1u32, request).unwrap();
+    expect_event!(events, WebSocketEventKind::Reconnecting { attempt: 1, .. });
+    assert_eq!(
+        context.connections::<u32>().collect::<Vec<_>>(),
+        vec![(1, ConnectionState::Connecting)]
+    );
+
+    handle.cancel();
+    expect_event!(events, WebSocketEventKind::Cancelled);
+    assert!(events.recv_timeout(Duration::from_millis(100)).is_none());
+    assert_eq!(context.connections::<u32>().count(), 0);
+}
+
+#[test]
+fn reconnect_after_server_closes() {
+    let connections = AtomicUsize::new(0);
+    let port = serve(move |socket| {
+        let first = connections.fetch_add(1, Ordering::SeqCst) == 0;
+        async move {
+            if first {
+                let mut ws = tokio_tungstenite::accept_async(socket).await.unwrap();
+                ws.close(None).await.unwrap();
+                while let Some(Ok(_)) = ws.next().await {}
+            } else {
+                echo_connection(socket).await;
+            }
+        }
+    });
+    let (mut context, events) = init_headless().unwrap();
+    let request =
+        ConnectRequest::new(format!("ws://127.0.0.1:{}/", port)).reconnect(ReconnectPolicy {
+            initial_delay: Duration::from_millis(10),
+            multiplier: 1.0,
+            max_delay: Duration::from_millis(10),
+            jitter: 0.0,
+            max_attempts: None,
+        });
+    context.start_connect((), request).unwrap();
+    expect_event!(events, WebSocketEventKind::Connected(_, None));
+    expect_event!(events, WebSocketEventKind::CloseMessage(None));
+    expect_event!(events, WebSocketEventKind::ConnectionClosed);
+    expect_event!(events, WebSocketEventKind::Reconnecting { attempt: 1, .. });
+    let mut sink = expect_event!(events, WebSocketEventKind::Reconnected(sink) => sink);
+
+    sink.send(String::from("hello")).unwrap();
+    expect_event!(events, WebSocketEventKind::Message(msg) => assert_eq!(msg, "hello"));
+}
+
+#[test]
+fn heartbeat_timeout() {
+    let port = silent_server();
+    let (mut context, events) = init_headless().unwrap();
+    let request = ConnectRequest::new(format!("ws://127.0.0.1:{}/", port)).heartbeat(Heartbeat {
+        interval: Duration::from_millis(50),
+        timeout: Duration::from_millis(300),
+        fallback_message: String::from("ping"),
+    });
+    context.start_connect((), request).unwrap();
+    let _sink = expect_event!(events, WebSocketEventKind::Connected(sink, None) => sink);
+    let connected = Instant::now();
+
+    expect_event!(events, WebSocketEventKind::Timeout);
+    // The pings went unanswered so nothing kept it alive, but it waited for the timeout. The
+    // clock starts around when `Connected` is posted, so allow a little slack.
+    assert!(connected.elapsed() >= Duration::from_millis(250));
+    assert!(events.recv_timeout(Duration::from_millis(100)).is_none());
+}
+
+#[test]
+fn heartbeat_zero_interval() {
+    let port = echo_server();
+    let (mut context, events) = init_headless().unwrap();
+    let request = ConnectRequest::new(format!("ws://127.0.0.1:{}/", port)).heartbeat(Heartbeat {
+        interval: Duration::ZERO,
+        timeout: TIMEOUT,
+        fallback_message: String::from("ping"),
+    });
+    context.start_connect((), request).unwrap();
+    let mut sink = expect_event!(events, WebSocketEventKind::Connected(sink, None) => sink);
+    sink.send(String::from("hello")).unwrap();
+    expect_event!(events, WebSocketEventKind::Message(msg) => assert_eq!(msg, "hello"));
+}
+
+#[test]
+fn huge_timeouts() {
+    let port = echo_server();
+    let (mut context, events) = init_headless().unwrap();
+    let request = ConnectRequest::new(format!("ws://127.0.0.1:{}/", port))
+        .connect_timeout(Duration::MAX)
+        .read_timeout(Duration::MAX)
+        .heartbeat(Heartbeat {
+            interval: Duration::MAX,
+            timeout: Duration::MAX,
+            fallback_message: String::from("ping"),
+        });
+    context.start_connect((), request).unwrap();
+    let mut sink = expect_event!(events, WebSocketEventKind::Connected(sink, None) => sink);
+    sink.send(String::from("hello")).unwrap();
+    expect_event!(events, WebSocketEventKind::Message(msg) => assert_eq!(msg, "hello"));
+}
+
+#[test]
+fn connect_timeout() {
+    let port = stalled_server();
+    let (mut context, events) = init_headless().unwrap();
+    let request = ConnectRequest::new(format!("ws://127.0.0.1:{}/", port))
+        .connect_timeout(Duration::from_millis(200));
+    context.start_connect((), request).unwrap();
+    expect_event!(events, WebSocketEventKind::ConnectionFailed(Error::Timeout));
+}
+
+#[test]
+fn read_timeout() {
+    let port = silent_server();
+    let (mut context, events) = init_headless().unwrap();
+    let request = ConnectRequest::new(format!("ws://127.0.0.1:{}/", port))
+        .read_timeout(Duration::from_millis(200));
+    context.start_connect((), request).unwrap();
+    let _sink = expect_event!(events, WebSocketEventKind::Connected(sink, None) => sink);
+    expect_event!(events, WebSocketEventKind::Timeout);
+}
+
+#[cfg(any(feature = "tls-native", feature = "tls-rustls"))]
+#[test]
+fn tls_rejects_unknown_certificate() {
+    let (_context, events) = tls_connect(TlsConfig::new());
+    expect_event!(events, WebSocketEventKind::ConnectionFailed(Error::Tls(_)));
+}
+
+#[cfg(any(feature = "tls-native", feature = "tls-rustls"))]
+#[test]
+fn tls_root_certificate() {
+    let tls = TlsConfig::new().root_certificate_pem(&include_bytes!("tls/ca.pem")[..]);
+    let (_context, events) = tls_connect(tls);
+    expect_event!(events, WebSocketEventKind::Connected(_, None));
+}
+
+#[cfg(any(feature = "tls-native", feature = "tls-rustls"))]
+#[test]
+fn tls_accept_invalid_certs() {
+    let (_context, events) = tls_connect(TlsConfig::new().danger_accept_invalid_certs(true));
+    expect_event!(events, WebSocketEventKind::Connected(_, None));
+}